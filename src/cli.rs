@@ -1,37 +1,173 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::protocol::{Endian, IMAGE_VERSION_OFFSET};
 
 pub const DEFAULT_VID: u16 = 0x054c;
 pub const DEFAULT_PID: u16 = 0x0ce6;
 
-#[derive(Parser, Debug)]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LogFormat {
+    Human,
+    Kv,
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "dualsense-updater",
     version,
     about = "Update DualSense firmware over USB. \n\nStandard usage:\n  dualsense-updater FW_IMAGE\n\nThe *-only options are for debugging individual steps."
 )]
 pub struct Args {
-    #[arg(long, value_parser = parse_u16, default_value_t = DEFAULT_VID)]
+    #[arg(long, env = "DS_UPDATER_VID", value_parser = parse_u16, default_value_t = DEFAULT_VID)]
     #[arg(help = "USB vendor ID (default 0x054c).")]
     pub vid: u16,
-    #[arg(long, value_parser = parse_u16, default_value_t = DEFAULT_PID)]
+    #[arg(long, env = "DS_UPDATER_PID", value_parser = parse_u16, default_value_t = DEFAULT_PID)]
     #[arg(help = "USB product ID (default 0x0ce6).")]
     pub pid: u16,
+    // Conflicts with --vid/--pid are checked in `parse_args`, so values from
+    // DS_UPDATER_VID/DS_UPDATER_PID don't count.
+    #[arg(long, action, help = "Use the first attached controller from the built-in list of known DualSense VID/PIDs.")]
+    pub auto_detect: bool,
+    #[arg(long, action, conflicts_with = "auto_detect", help = "Choose among all devices with the given VID, whatever their PID (always asks).")]
+    pub force_pid_any: bool,
     #[arg(value_name = "FW_IMAGE", default_value = "", help = "Firmware image path (required for update commands).")]
     pub fw_image: String,
+    #[arg(long, value_name = "BYTES", default_value_t = 0, help = "Skip this many bytes of FW_IMAGE before the 256-byte header, for images wrapped in a container.")]
+    pub image_offset: usize,
+    #[arg(long, value_name = "SIG", requires = "pubkey", help = "Refuse FW_IMAGE unless this file holds a valid ed25519 signature of it by --pubkey. Needs the signature feature.")]
+    pub verify_signature: Option<PathBuf>,
+    #[arg(long, value_name = "KEY", requires = "verify_signature", help = "ed25519 public key for --verify-signature, as 32 raw bytes or 64 hex digits.")]
+    pub pubkey: Option<PathBuf>,
+    #[arg(long, value_name = "OFFSET", value_parser = parse_usize, default_value_t = IMAGE_VERSION_OFFSET, help = "Read the image's firmware version from this offset instead of 0x78, for image formats that have moved it.")]
+    pub image_version_offset: usize,
+    #[arg(long, value_enum, default_value_t = Endian::Little, help = "Byte order of the image's firmware version word.")]
+    pub version_endian: Endian,
+    #[arg(long, action, help = "Print what FW_IMAGE contains without touching any device.")]
+    pub inspect: bool,
+    #[arg(long, action, help = "List the devices matching --vid/--pid (any PID with --force-pid-any) and exit, without opening them.")]
+    pub list_devices: bool,
+    #[arg(long, value_name = "DIR", help = "List the firmware images in a directory with their versions and sizes (no device needed).")]
+    pub list_images: Option<PathBuf>,
     #[arg(long = "start-update-only", action, help = "Only run StartUpdate using the first 256 bytes of the image.")]
     pub start_update: bool,
     #[arg(long = "write-update-image-only", action, help = "Only run WriteUpdateImage with 0x8000-byte chunks.")]
     pub write_update_image: bool,
+    #[arg(long, value_name = "CHUNK", default_value_t = 0, help = "With --write-update-image-only, skip to this 0x8000-byte chunk index (experimental).")]
+    pub resume_from: usize,
     #[arg(long = "verify-update-image-only", action, help = "Only run VerifyUpdateImage and wait for completion.")]
     pub verify_update_image: bool,
     #[arg(long = "finalize-update-only", action, help = "Only run FinalizeUpdate (no polling).")]
     pub finalize_update: bool,
-    #[arg(short = 'v', long, action, help = "Enable verbose USB debug output.")]
-    pub verbose: bool,
+    #[arg(long, action, help = "In the full flow, stop after VerifyUpdateImage without sending FinalizeUpdate.")]
+    pub no_finalize: bool,
+    #[arg(long, action, help = "In the full flow, skip VerifyUpdateImage and finalize the unchecked image. Dangerous: asks first unless --force is given.")]
+    pub no_verify: bool,
+    #[arg(long, action, conflicts_with = "no_verify", help = "If the controller rejects the image in VerifyUpdateImage, report it in the summary but don't finalize and don't exit nonzero.")]
+    pub continue_on_verify_error: bool,
+    #[arg(long, action, help = "After FinalizeUpdate, wait for the controller to restart and fail unless it reports the image's firmware version.")]
+    pub strict_verify: bool,
+    #[arg(long, value_name = "MS", default_value_t = 10, help = "Sleep between update status polls (1-1000ms). Longer means less USB traffic on flaky hubs; shorter can finish faster on responsive devices.")]
+    pub poll_interval_ms: u64,
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..), help = "Give up on VerifyUpdateImage if the controller is still verifying after this many seconds.")]
+    pub verify_timeout_secs: u64,
+    #[arg(long, value_name = "US", default_value_t = 0, help = "Pause between the feature reports of an update command and between image chunks, in microseconds. Try a small value if flashing fails with SFEATURE I/O errors.")]
+    pub inter_packet_delay_us: u64,
+    #[arg(long, action, help = "Poll and print the update status report until interrupted.")]
+    pub watch_status: bool,
+    #[arg(long, value_name = "MS", default_value_t = 500, help = "Poll interval for --watch-status in milliseconds.")]
+    pub interval: u64,
+    #[arg(long, value_name = "PATH", conflicts_with = "repeat", help = "Record every transfer of the flash to a log file for later --replay.")]
+    pub record: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", help = "After a successful flash, write a receipt with the image path, size, digest, device serial and target version to this file.")]
+    pub checksum_file: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["record", "repeat"], help = "Run the flash against a log from --record instead of a controller.")]
+    pub replay: Option<PathBuf>,
+    #[arg(short = 'v', long, action = clap::ArgAction::Count, help = "Log more: -v for update timings, -vv for USB debug output, -vvv for full hexdumps of every report.")]
+    pub verbose: u8,
+    #[arg(long, value_name = "PATH", help = "Write every command, status report and outcome of the flash to this file as JSON lines, whatever the verbosity.")]
+    pub event_log: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", help = "Also write debug logs, with timestamps, to this file.")]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = LogFormat::Human, help = "Log line format; kv prints key=value pairs for grepping.")]
+    pub log_format: LogFormat,
+    #[arg(short = 'q', long, visible_alias = "summary-only", action, help = "Only print warnings, errors and the final result while flashing.")]
+    pub quiet: bool,
+    #[arg(long, action, help = "Disable colored output (also honors NO_COLOR).")]
+    pub no_color: bool,
     #[arg(long, action, help = "Print current firmware info and exit.")]
     pub print_firmware_info: bool,
-    #[arg(long, default_value = "", help = "Exact HID device path to open.")]
+    #[arg(long, action, help = "Hexdump the raw firmware info report with known fields annotated.")]
+    pub dump_firmware_info: bool,
+    #[arg(long, value_name = "PATH", help = "Save the raw firmware info report to a file.")]
+    pub save_info: Option<PathBuf>,
+    #[arg(long, value_name = "REPORT_ID", value_parser = parse_u8, conflicts_with_all = ["start_update", "write_update_image", "verify_update_image", "finalize_update"], help = "Read one feature report by ID (e.g. 0x20), hexdump it and exit. Read-only; what most reports mean is unknown.")]
+    pub print_report: Option<u8>,
+    #[arg(long, value_name = "N", default_value_t = 64, requires = "print_report", help = "Bytes to request with --print-report, including the report ID.")]
+    pub length: usize,
+    #[arg(long, action, conflicts_with_all = ["start_update", "write_update_image", "verify_update_image", "finalize_update", "print_report", "reset_device"], help = "Read the firmware info and update status reports once, time them and exit. Sends nothing to the device.")]
+    pub probe: bool,
+    #[arg(long, action, conflicts_with_all = ["start_update", "write_update_image", "verify_update_image", "finalize_update", "print_report"], help = "Soft-reset the controller over HID instead of re-plugging it. Not yet supported: no reset report is known.")]
+    pub reset_device: bool,
+    #[arg(long, action, conflicts_with_all = ["start_update", "write_update_image", "verify_update_image", "finalize_update", "print_report", "probe", "reset_device"], help = "Back out of an update after --start-update-only. Not possible: the protocol has no abort command, so this prints how to recover instead.")]
+    pub abort_update: bool,
+    #[arg(long, num_args = 2, value_names = ["A", "B"], help = "Compare two reports saved with --save-info (no device needed).")]
+    pub diff_info: Vec<PathBuf>,
+    #[arg(long, num_args = 2, value_names = ["A", "B"], help = "Compare two firmware image files (no device needed).")]
+    pub compare_image: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "Output format for --print-firmware-info and the flash summary.")]
+    pub format: OutputFormat,
+    #[arg(long, env = "DS_UPDATER_PATH", default_value = "", help = "Exact HID device path to open: /dev/hidrawN on Linux, \\\\?\\HID#VID_054C&PID_0CE6#... on Windows (case-insensitive), DevSrvsID:N on macOS.")]
     pub path: String,
+    #[arg(long, action, help = "Don't take the per-device lock that keeps two dualsense-updater processes from talking to the same controller at once.")]
+    pub no_device_lock: bool,
+    #[arg(long, value_name = "PATH", help = "Read defaults from this config file instead of ./dualsense-updater.toml or ~/.config/dualsense-updater.toml.")]
+    pub config: Option<PathBuf>,
+    #[arg(long, value_name = "SECS", help = "Wait up to this many seconds for a controller to be plugged in.")]
+    pub wait_for_device: Option<u64>,
+    #[arg(long, default_value = "", help = "Serial number of the device to open.")]
+    pub serial: String,
+    #[arg(long, value_name = "SERIAL", help = "After opening the device, abort before sending anything unless its serial number is this one.")]
+    pub confirm_serial: Option<String>,
+    #[arg(short = 'y', long, action, help = "Answer yes to prompts; fail instead of asking which device to use.")]
+    pub yes: bool,
+    #[arg(long, action, conflicts_with = "repeat", help = "Never read stdin: fail with an error naming the flag to pass wherever a prompt would be needed.")]
+    pub headless: bool,
+    #[arg(long, value_name = "SECS", help = "Answer no to the flash confirmation if nothing is entered within this many seconds.")]
+    pub prompt_timeout: Option<u64>,
+    #[arg(long, value_name = "N", default_value_t = 0, help = "On a transient failure, run the whole update again up to N more times.")]
+    pub retry_whole_update: u32,
+    #[arg(long, action, help = "After each flash, wait for the next controller and flash it with the same image.")]
+    pub repeat: bool,
+    #[arg(long, action, help = "Proceed despite safety checks that would otherwise abort.")]
+    pub force: bool,
+    #[arg(long, action, help = "Do not read the battery level before updating.")]
+    pub skip_battery_check: bool,
+    #[arg(long, value_name = "PERCENT", default_value_t = 20, value_parser = clap::value_parser!(u8).range(0..=100), help = "Refuse to flash below this battery level unless the controller is charging.")]
+    pub min_battery: u8,
+}
+
+fn parse_u8(value: &str) -> Result<u8, String> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16).map_err(|e| e.to_string())
+    } else {
+        value.parse::<u8>().map_err(|e| e.to_string())
+    }
+}
+
+fn parse_usize(value: &str) -> Result<usize, String> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).map_err(|e| e.to_string())
+    } else {
+        value.parse::<usize>().map_err(|e| e.to_string())
+    }
 }
 
 fn parse_u16(value: &str) -> Result<u16, String> {
@@ -0,0 +1,100 @@
+//! `dualsense-updater.toml`: defaults for settings a shared bench always
+//! passes, so they don't have to be repeated on every run. Keys are named
+//! after the flags they stand in for:
+//!
+//! ```toml
+//! vid = 0x054c
+//! pid = 0x0ce6
+//! image = "firmware/FWUPDATE0520.bin"
+//! poll-interval-ms = 20
+//! retry-whole-update = 2
+//! ```
+//!
+//! A value only applies when the flag wasn't given on the command line or
+//! through its environment variable.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::cli::Args;
+
+pub const CONFIG_FILE_NAME: &str = "dualsense-updater.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// Used as FW_IMAGE when none is given. Relative to the config file.
+    pub image: Option<PathBuf>,
+    pub poll_interval_ms: Option<u64>,
+    pub retry_whole_update: Option<u32>,
+}
+
+impl Config {
+    /// Reads `explicit` if given, which must exist. Otherwise the first of
+    /// `./dualsense-updater.toml` and `$XDG_CONFIG_HOME/dualsense-updater.toml`
+    /// (`~/.config` when unset) that exists, or `None` if neither does.
+    pub fn load(explicit: Option<&Path>) -> Result<Option<(PathBuf, Self)>, String> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match search_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|err| format!("can't read config file {}: {}", path.display(), err))?;
+        let mut config: Self = toml::from_str(&text)
+            .map_err(|err| format!("invalid config file {}: {}", path.display(), err))?;
+        if let Some(image) = &mut config.image
+            && let Some(dir) = path.parent()
+        {
+            *image = dir.join(&*image);
+        }
+        Ok(Some((path, config)))
+    }
+
+    /// Fills in the settings for which `is_default` says `args` still holds
+    /// the built-in default.
+    pub fn apply(self, args: &mut Args, is_default: impl Fn(&str) -> bool) {
+        if let Some(vid) = self.vid
+            && is_default("vid")
+        {
+            args.vid = vid;
+        }
+        if let Some(pid) = self.pid
+            && is_default("pid")
+        {
+            args.pid = pid;
+        }
+        if let Some(image) = self.image
+            && is_default("fw_image")
+        {
+            args.fw_image = image.to_string_lossy().into_owned();
+        }
+        if let Some(ms) = self.poll_interval_ms
+            && is_default("poll_interval_ms")
+        {
+            args.poll_interval_ms = ms;
+        }
+        if let Some(count) = self.retry_whole_update
+            && is_default("retry_whole_update")
+        {
+            args.retry_whole_update = count;
+        }
+    }
+}
+
+fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = config_home {
+        paths.push(dir.join(CONFIG_FILE_NAME));
+    }
+    paths
+}
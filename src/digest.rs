@@ -0,0 +1,63 @@
+//! Digest of the image bytes actually sent during WriteUpdateImage. CRC-32 by
+//! default; the `sha256` feature switches to SHA-256.
+
+#[cfg(feature = "sha256")]
+use sha2::{Digest, Sha256};
+
+pub struct WriteDigest {
+    #[cfg(not(feature = "sha256"))]
+    crc: u32,
+    #[cfg(feature = "sha256")]
+    sha: Sha256,
+}
+
+impl WriteDigest {
+    #[cfg(not(feature = "sha256"))]
+    pub fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+
+    #[cfg(feature = "sha256")]
+    pub fn new() -> Self {
+        Self { sha: Sha256::new() }
+    }
+
+    #[cfg(not(feature = "sha256"))]
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    #[cfg(feature = "sha256")]
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha.update(data);
+    }
+
+    /// Returns the digest as `<algorithm>:<hex>`.
+    #[cfg(not(feature = "sha256"))]
+    pub fn finish(self) -> String {
+        format!("crc32:{:08x}", !self.crc)
+    }
+
+    #[cfg(feature = "sha256")]
+    pub fn finish(self) -> String {
+        let hex = self
+            .sha
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("sha256:{}", hex)
+    }
+}
+
+impl Default for WriteDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,3 +1,5 @@
+use serde::{Serialize, Serializer};
+
 use crate::protocol::UpdateCommand;
 
 #[derive(Debug, thiserror::Error)]
@@ -8,37 +10,280 @@ pub enum AppError {
     Io(#[from] std::io::Error),
     #[error("Device not found for VID:PID {vid:04x}:{pid:04x}")]
     DeviceNotFound { vid: u16, pid: u16 },
+    #[error("No known DualSense controller found")]
+    NoKnownDeviceFound,
     #[error("No device path matched {0}")]
     DevicePathNotMatched(String),
+    #[error("No device matched serial number {0}")]
+    DeviceSerialNotMatched(String),
+    #[error("Controller at {path} is in use by another dualsense-updater (pass --no-device-lock to skip this check)")]
+    DeviceBusy { path: String },
+    #[error("Opened device has serial number {got}, expected {expected}")]
+    SerialMismatch { expected: String, got: String },
+    #[error("{0} devices matched; pass --path or --serial to choose one")]
+    AmbiguousDevice(usize),
     #[error("FW_IMAGE is required for update commands")]
     MissingFirmwareImageForUpdate,
     #[error("FW_IMAGE is required when no flags are provided")]
     MissingFirmwareImageForInteractive,
-    #[error("Firmware image is too small to read version")]
-    FirmwareImageTooSmall,
+    #[error("Version offset 0x{offset:x} is past the end of the {file_len}-byte image")]
+    VersionOffsetOutOfRange { offset: usize, file_len: usize },
     #[error("Firmware image must be at least 256 bytes")]
     FirmwareImageTooSmallForHeader,
+    #[error("Firmware image size {len} bytes is implausible for DualSense firmware")]
+    FirmwareImageImplausibleSize { len: usize },
+    #[error("Firmware image is malformed: {0}")]
+    FirmwareImageMalformed(&'static str),
+    #[error("Firmware image signature does not match the public key; refusing to flash it")]
+    ImageSignatureInvalid,
+    #[error("Couldn't check the image signature: {0}")]
+    SignatureCheckFailed(String),
+    #[error("Firmware image is gzip-compressed but couldn't be decompressed: {0}")]
+    ImageDecompressFailed(String),
+    #[error("Image offset {offset} is past the end of the {len}-byte file")]
+    ImageOffsetOutOfRange { offset: usize, len: usize },
+    #[error("Firmware image version 0x{0:04x} is not plausible")]
+    ImageVersionImplausible(u16),
     #[error("Update stream must be 256 bytes, got {0}")]
     InvalidUpdateStreamLength(usize),
     #[error("Update image must be <= 0x8000 bytes, got {0}")]
     UpdateImageTooLarge(usize),
+    #[error("Feature report 0x{report_id:02x} returned {got} bytes, expected {expected}")]
+    ShortFeatureReport {
+        report_id: u8,
+        got: usize,
+        expected: usize,
+    },
     #[error("Firmware info report too short: {0} bytes")]
     FirmwareInfoTooShort(usize),
     #[error("Firmware info payload too short: {0} bytes")]
     FirmwareInfoPayloadTooShort(usize),
+    #[error("Firmware info report layout not recognized: {0} bytes")]
+    FirmwareInfoUnrecognized(usize),
+    #[error("Controller looks to be in recovery mode after an interrupted update; re-run the full update with the same image to complete it")]
+    DeviceInRecoveryMode,
     #[error("Update status report is empty")]
     UpdateStatusEmpty,
     #[error("Update status report malformed: {0} bytes")]
     UpdateStatusMalformed(usize),
+    #[error("Update status report has unknown command byte 0x{0:02x}")]
+    UnknownStatusCommandByte(u8),
     #[error("Unexpected update status command: {0:?} (expected {1:?})")]
     UnexpectedUpdateStatusCommand(UpdateCommand, UpdateCommand),
+    #[error("Status report for {got:?} arrived while writing chunk {chunk} ({expected:?}); the device fell out of step")]
+    StatusCommandDrift {
+        got: UpdateCommand,
+        expected: UpdateCommand,
+        chunk: usize,
+    },
+    #[error("Battery too low to update: {level}% (at least {required}% required)")]
+    BatteryTooLow { level: u8, required: u8 },
+    #[error("Refusing to finalize: the image was not verified in this session (use --force to override)")]
+    FinalizeWithoutVerify,
+    #[error("Chunk {chunk} was retried {count} times; giving up")]
+    TooManyRetries { chunk: usize, count: u32 },
+    #[error("{command:?} still not done after {:.1}s; giving up", elapsed.as_secs_f64())]
+    UpdateTimeout {
+        command: UpdateCommand,
+        elapsed: std::time::Duration,
+    },
+    #[error("Cancelled during {during:?}")]
+    Cancelled { during: UpdateCommand },
+    #[error("Replay log line {line} is malformed")]
+    ReplayLogMalformed { line: usize },
+    #[error("Replay diverged at log line {line}: expected {expected}, got {got}")]
+    ReplayDiverged {
+        line: usize,
+        expected: String,
+        got: String,
+    },
     #[error("Update failed: {0}")]
     UpdateFailed(UpdateFailure),
+    #[error("--headless: {what} would need an answer on stdin; pass {answer_with} instead")]
+    HeadlessPrompt {
+        what: &'static str,
+        answer_with: &'static str,
+    },
+    #[error("Resetting the controller over HID is not yet implemented on this model: no reset report is known")]
+    ResetNotSupported,
+    #[error("An update can't be aborted: the protocol has no abort command")]
+    AbortNotSupported,
+    #[error("Controller did not disconnect and come back within {secs}s")]
+    ReconnectTimeout { secs: u64 },
+    #[error(
+        "Controller reports firmware version {got:#06x} after the update, expected {expected:#06x}"
+    )]
+    PostUpdateVersionMismatch { expected: u16, got: u16 },
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
 
-#[derive(Debug, thiserror::Error)]
+impl AppError {
+    /// Stable name of the variant, used as the `kind` of JSON errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Hid(..) => "Hid",
+            AppError::Io(..) => "Io",
+            AppError::DeviceNotFound { .. } => "DeviceNotFound",
+            AppError::NoKnownDeviceFound => "NoKnownDeviceFound",
+            AppError::DevicePathNotMatched(..) => "DevicePathNotMatched",
+            AppError::DeviceSerialNotMatched(..) => "DeviceSerialNotMatched",
+            AppError::DeviceBusy { .. } => "DeviceBusy",
+            AppError::SerialMismatch { .. } => "SerialMismatch",
+            AppError::AmbiguousDevice(..) => "AmbiguousDevice",
+            AppError::MissingFirmwareImageForUpdate => "MissingFirmwareImageForUpdate",
+            AppError::MissingFirmwareImageForInteractive => "MissingFirmwareImageForInteractive",
+            AppError::VersionOffsetOutOfRange { .. } => "VersionOffsetOutOfRange",
+            AppError::FirmwareImageTooSmallForHeader => "FirmwareImageTooSmallForHeader",
+            AppError::FirmwareImageImplausibleSize { .. } => "FirmwareImageImplausibleSize",
+            AppError::FirmwareImageMalformed(..) => "FirmwareImageMalformed",
+            AppError::ImageDecompressFailed(..) => "ImageDecompressFailed",
+            AppError::ImageSignatureInvalid => "ImageSignatureInvalid",
+            AppError::SignatureCheckFailed(..) => "SignatureCheckFailed",
+            AppError::ImageOffsetOutOfRange { .. } => "ImageOffsetOutOfRange",
+            AppError::ImageVersionImplausible(..) => "ImageVersionImplausible",
+            AppError::InvalidUpdateStreamLength(..) => "InvalidUpdateStreamLength",
+            AppError::UpdateImageTooLarge(..) => "UpdateImageTooLarge",
+            AppError::ShortFeatureReport { .. } => "ShortFeatureReport",
+            AppError::FirmwareInfoTooShort(..) => "FirmwareInfoTooShort",
+            AppError::FirmwareInfoPayloadTooShort(..) => "FirmwareInfoPayloadTooShort",
+            AppError::FirmwareInfoUnrecognized(..) => "FirmwareInfoUnrecognized",
+            AppError::DeviceInRecoveryMode => "DeviceInRecoveryMode",
+            AppError::UpdateStatusEmpty => "UpdateStatusEmpty",
+            AppError::UpdateStatusMalformed(..) => "UpdateStatusMalformed",
+            AppError::UnknownStatusCommandByte(..) => "UnknownStatusCommandByte",
+            AppError::UnexpectedUpdateStatusCommand(..) => "UnexpectedUpdateStatusCommand",
+            AppError::StatusCommandDrift { .. } => "StatusCommandDrift",
+            AppError::BatteryTooLow { .. } => "BatteryTooLow",
+            AppError::FinalizeWithoutVerify => "FinalizeWithoutVerify",
+            AppError::TooManyRetries { .. } => "TooManyRetries",
+            AppError::UpdateTimeout { .. } => "UpdateTimeout",
+            AppError::Cancelled { .. } => "Cancelled",
+            AppError::ReplayLogMalformed { .. } => "ReplayLogMalformed",
+            AppError::ReplayDiverged { .. } => "ReplayDiverged",
+            AppError::UpdateFailed(..) => "UpdateFailed",
+            AppError::HeadlessPrompt { .. } => "HeadlessPrompt",
+            AppError::ResetNotSupported => "ResetNotSupported",
+            AppError::AbortNotSupported => "AbortNotSupported",
+            AppError::ReconnectTimeout { .. } => "ReconnectTimeout",
+            AppError::PostUpdateVersionMismatch { .. } => "PostUpdateVersionMismatch",
+        }
+    }
+
+    /// Whether running the whole update again could plausibly succeed.
+    /// Rejections of the image itself (authentication, version, model) and
+    /// local problems like a bad file or missing permissions never are.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::Hid(err) => HidErrno::classify(err) != Some(HidErrno::Eacces),
+            AppError::ShortFeatureReport { .. }
+            | AppError::FirmwareInfoTooShort(_)
+            | AppError::FirmwareInfoPayloadTooShort(_)
+            | AppError::UpdateStatusEmpty
+            | AppError::UpdateStatusMalformed(_)
+            | AppError::UnexpectedUpdateStatusCommand(_, _)
+            | AppError::StatusCommandDrift { .. }
+            | AppError::TooManyRetries { .. } => true,
+            AppError::UpdateFailed(failure) => failure.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the controller dropped off the bus: a HID call failed with
+    /// ENODEV, or reopening it found nothing.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            AppError::Hid(err) => HidErrno::classify(err) == Some(HidErrno::Enodev),
+            AppError::DeviceNotFound { .. } | AppError::DevicePathNotMatched(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// `--format json` errors: a stable `kind`, the step and device status for
+/// update rejections, and the human-readable `message`, e.g.
+/// `{"kind":"UpdateFailed","phase":"StartUpdate","reason":"HeaderVersionCheckError","message":"..."}`.
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Fields<'a> {
+            kind: &'static str,
+            #[serde(flatten)]
+            failure: Option<&'a UpdateFailure>,
+            message: String,
+        }
+
+        Fields {
+            kind: self.kind(),
+            failure: match self {
+                AppError::UpdateFailed(failure) => Some(failure),
+                _ => None,
+            },
+            message: self.to_string(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The OS error behind a failed HID call, for the cases worth telling apart.
+/// The hidraw backend only reports these as text (e.g. "ioctl (SFEATURE):
+/// Input/output error"), so the message is matched as well as the errno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidErrno {
+    Eio,
+    Eacces,
+    Epipe,
+    Enodev,
+}
+
+impl HidErrno {
+    pub fn classify(err: &hidapi::HidError) -> Option<Self> {
+        match err {
+            hidapi::HidError::IoError { error } => match error.kind() {
+                std::io::ErrorKind::PermissionDenied => Some(Self::Eacces),
+                std::io::ErrorKind::BrokenPipe => Some(Self::Epipe),
+                _ => Self::from_message(&error.to_string()),
+            },
+            hidapi::HidError::HidApiError { message } => Self::from_message(message),
+            _ => None,
+        }
+    }
+
+    fn from_message(message: &str) -> Option<Self> {
+        if message.contains("Input/output error") {
+            Some(Self::Eio)
+        } else if message.contains("Permission denied") {
+            Some(Self::Eacces)
+        } else if message.contains("Broken pipe") {
+            Some(Self::Epipe)
+        } else if message.contains("No such device") {
+            Some(Self::Enodev)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Eio => "EIO",
+            Self::Eacces => "EACCES",
+            Self::Epipe => "EPIPE",
+            Self::Enodev => "ENODEV",
+        }
+    }
+
+    pub fn remedy(self) -> &'static str {
+        match self {
+            Self::Eio => "the controller rejected the request; reconnect it over USB and retry",
+            Self::Eacces => "no permission to access the device; grant access to its hidraw node (e.g. with a udev rule) or run as root",
+            Self::Epipe => "the USB connection stalled; try another cable or port",
+            Self::Enodev => "the controller was disconnected; plug it back in",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(tag = "phase", content = "reason")]
 pub enum UpdateFailure {
     #[error("StartUpdate failed: {0}")]
     StartUpdate(StartUpdateError),
@@ -46,12 +291,32 @@ pub enum UpdateFailure {
     WriteUpdateImage(WriteUpdateImageError),
     #[error("VerifyUpdateImage failed: {0}")]
     VerifyUpdateImage(VerifyUpdateImageError),
-    #[allow(dead_code)]
     #[error("FinalizeUpdate failed: {0}")]
     FinalizeUpdate(FinalizeUpdateError),
 }
 
-#[derive(Debug, Copy, Clone, thiserror::Error)]
+impl UpdateFailure {
+    fn is_retryable(&self) -> bool {
+        match self {
+            UpdateFailure::StartUpdate(err) => !matches!(
+                err,
+                StartUpdateError::HeaderCmacCheckError
+                    | StartUpdateError::HeaderVersionCheckError
+                    | StartUpdateError::HeaderCapabilityInfoError
+            ),
+            UpdateFailure::WriteUpdateImage(_) => true,
+            UpdateFailure::VerifyUpdateImage(err) => matches!(
+                err,
+                VerifyUpdateImageError::VerifyCommonParamError
+                    | VerifyUpdateImageError::VerifyOtherError
+            ),
+            UpdateFailure::FinalizeUpdate(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum StartUpdateError {
     #[error("HEADER_CMAC_CHECK_ERROR")]
     HeaderCmacCheckError,
@@ -69,7 +334,8 @@ pub enum StartUpdateError {
     HeaderOtherError,
 }
 
-#[derive(Debug, Copy, Clone, thiserror::Error)]
+#[derive(Debug, Copy, Clone, Serialize, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum WriteUpdateImageError {
     #[error("WRITE_IMAGE_FLASH_WRITE_ERROR")]
     WriteImageFlashWriteError,
@@ -81,7 +347,8 @@ pub enum WriteUpdateImageError {
     WriteImageOtherError,
 }
 
-#[derive(Debug, Copy, Clone, thiserror::Error)]
+#[derive(Debug, Copy, Clone, Serialize, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum VerifyUpdateImageError {
     #[error("VERIFY_HEADER_CMAC_CHECK_ERROR")]
     VerifyHeaderCmacCheckError,
@@ -97,9 +364,8 @@ pub enum VerifyUpdateImageError {
     VerifyOtherError,
 }
 
-#[derive(Debug, Copy, Clone, thiserror::Error)]
+#[derive(Debug, Copy, Clone, Serialize, thiserror::Error)]
 pub enum FinalizeUpdateError {
-    #[allow(dead_code)]
     #[error("FINALIZE_OTHER_ERROR")]
     FinalizeOtherError,
 }
@@ -0,0 +1,126 @@
+//! `--event-log`: a JSON-lines record of a flash for bug reports, written
+//! whatever the console verbosity. Each line is one event with a wall-clock
+//! timestamp and the time since the log was opened, e.g.
+//!
+//! ```text
+//! {"command":"WriteUpdateImage","elapsed_ms":52.113,"event":"status","raw":"f5010300","status":3,"ts_ms":1760600000123}
+//! ```
+//!
+//! Events come through the `ProgressSink` hooks, so the log sees the same
+//! protocol steps as the console without depending on what it prints:
+//!
+//! - `attempt_started`: a flash attempt begins (with `attempt`, from 0)
+//! - `phase_started`: StartUpdate, WriteUpdateImage, VerifyUpdateImage or
+//!   FinalizeUpdate begins
+//! - `command_sent`: one 0xF4 command report, with its payload length
+//! - `status`: one 0xF5 status report, with the raw bytes
+//! - `chunk_written`: a 0x8000-byte chunk was acknowledged
+//! - `attempt_finished`: the outcome, or the error as in `--format json`
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::error::{AppError, Result};
+use crate::progress::ProgressSink;
+use crate::protocol::{UpdateCommand, UpdateStatus, WriteUpdateStatusCode};
+
+pub struct EventLog {
+    file: Mutex<LineWriter<File>>,
+    opened: Instant,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            file: Mutex::new(LineWriter::new(File::create(path)?)),
+            opened: Instant::now(),
+        }))
+    }
+
+    /// Appends one event. `fields` must be a JSON object. A failed write is
+    /// only logged: losing the event log isn't worth failing a flash over.
+    pub fn record(&self, event: &str, fields: Value) {
+        let mut line = json!({
+            "ts_ms": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis()),
+            "elapsed_ms": self.opened.elapsed().as_micros() as f64 / 1000.0,
+            "event": event,
+        });
+        if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+            line.extend(fields);
+        }
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(err) = writeln!(file, "{}", line) {
+            log::warn!("Failed to write to the event log: {err}");
+        }
+    }
+
+    pub fn attempt_finished(&self, result: std::result::Result<&str, &AppError>) {
+        match result {
+            Ok(outcome) => self.record("attempt_finished", json!({ "outcome": outcome })),
+            Err(err) => self.record("attempt_finished", json!({ "error": err })),
+        }
+    }
+
+    /// Wraps `inner` so every progress event is also recorded here.
+    pub fn sink(
+        self: &Arc<Self>,
+        inner: Box<dyn ProgressSink + Send>,
+    ) -> Box<dyn ProgressSink + Send> {
+        Box::new(LoggingProgress {
+            log: Arc::clone(self),
+            inner,
+        })
+    }
+}
+
+struct LoggingProgress {
+    log: Arc<EventLog>,
+    inner: Box<dyn ProgressSink + Send>,
+}
+
+impl ProgressSink for LoggingProgress {
+    fn phase_started(&self, command: UpdateCommand) {
+        self.log
+            .record("phase_started", json!({ "command": format!("{:?}", command) }));
+        self.inner.phase_started(command);
+    }
+
+    fn command_sent(&self, command: UpdateCommand, payload: &[u8]) {
+        self.log.record(
+            "command_sent",
+            json!({ "command": format!("{:?}", command), "len": payload.len() }),
+        );
+        self.inner.command_sent(command, payload);
+    }
+
+    fn status_received(&self, status: &UpdateStatus) {
+        self.log.record(
+            "status",
+            json!({
+                "command": format!("{:?}", status.command),
+                "status": status.status_raw,
+                "raw": status.raw.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            }),
+        );
+        self.inner.status_received(status);
+    }
+
+    fn verify_waiting(&self, elapsed: Duration, timeout: Duration) {
+        self.inner.verify_waiting(elapsed, timeout);
+    }
+
+    fn chunk_written(&self, idx: usize, status: WriteUpdateStatusCode) {
+        self.log.record(
+            "chunk_written",
+            json!({ "chunk": idx, "status": status.name(), "status_raw": status as u8 }),
+        );
+        self.inner.chunk_written(idx, status);
+    }
+}
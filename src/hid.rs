@@ -1,84 +1,317 @@
 use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use hidapi::{HidApi, HidDevice};
+use hidapi::{DeviceInfo, HidApi, HidDevice, MAX_REPORT_DESCRIPTOR_SIZE};
+use serde::Serialize;
 
 use crate::error::{AppError, Result};
 use crate::protocol::{
-    decode_ascii, FirmwareInfo, UpdateCommand, UpdateStatus, REPORT_ID_FIRMWARE_INFO,
+    feature_report_len, BatteryStatus, FirmwareInfo, Model, UpdateCommand, UpdateStatus,
+    FIRMWARE_INFO_MIN_LEN, KNOWN_DEVICES, MAX_UPDATE_PAYLOAD, REPORT_ID_FIRMWARE_INFO,
     REPORT_ID_UPDATE_COMMAND, REPORT_ID_UPDATE_STATUS,
 };
 
+/// The feature-report operations the update sequence needs from a device.
+pub trait HidTransport {
+    fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()>;
+    fn get_update_status(&self, length: usize) -> Result<UpdateStatus>;
+    fn get_firmware_info(&self) -> Result<FirmwareInfo>;
+
+    /// Largest payload `send_update_command` sends per report.
+    fn max_update_payload(&self) -> usize {
+        MAX_UPDATE_PAYLOAD
+    }
+}
+
+/// An open controller. It doesn't keep a `HidApi` around: hidapi initializes
+/// its C library once and never tears it down, so a `HidDevice` stays valid
+/// after the `HidApi` it came from is dropped.
 pub struct DualSenseHid {
-    _api: HidApi,
     dev: HidDevice,
+    max_update_payload: usize,
+    inter_packet_delay: Duration,
+    /// Held while the device is open, when `open` took one.
+    _lock: Option<DeviceLock>,
+}
+
+/// Whether `DualSenseHid::open` takes the per-device lock. On unless
+/// `--no-device-lock` turns it off.
+static DEVICE_LOCKING: AtomicBool = AtomicBool::new(true);
+
+pub fn set_device_locking(enabled: bool) {
+    DEVICE_LOCKING.store(enabled, Ordering::Relaxed);
+}
+
+/// An advisory lock on a file in the temp directory named after the device
+/// path, so two processes can't interleave feature reports on one controller.
+/// The OS drops it when the file is closed, including if the process dies.
+struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    fn acquire(device_path: &str) -> Result<Self> {
+        let name: String = normalize_path(device_path)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let lock_path: PathBuf =
+            std::env::temp_dir().join(format!("dualsense-updater-{name}.lock"));
+        // Another user's lock file may not be writable, but a read-only
+        // handle can still be locked.
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .or_else(|_| File::open(&lock_path))?;
+        match file.try_lock() {
+            Ok(()) => {
+                log::debug!("Locked {} ({})", device_path, lock_path.display());
+                Ok(Self { _file: file })
+            }
+            Err(TryLockError::WouldBlock) => Err(AppError::DeviceBusy {
+                path: device_path.to_string(),
+            }),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+}
+
+/// A matching device, as listed by `DualSenseHid::list`. Nothing is opened
+/// to get these; they come from the OS device list.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSummary {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub path: String,
+    pub serial: String,
+    pub product: String,
+    /// -1 where the backend doesn't report one (e.g. some macOS devices).
+    pub interface: i32,
+    pub usage_page: u16,
+    pub usage: u16,
+}
+
+impl DeviceSummary {
+    fn from_info(info: &DeviceInfo) -> Self {
+        Self {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            path: info.path().to_string_lossy().to_string(),
+            serial: info.serial_number().unwrap_or("").to_string(),
+            product: info.product_string().unwrap_or("").to_string(),
+            interface: info.interface_number(),
+            usage_page: info.usage_page(),
+            usage: info.usage(),
+        }
+    }
 }
 
-pub fn find_first_device_path(vid: u16, pid: u16) -> Result<String> {
+/// How often `wait_for_reconnect` re-enumerates. Short enough not to miss a
+/// restart, which takes the controller off the bus for well over this.
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for a controller to drop off the bus and come back, as it does when
+/// it restarts after FinalizeUpdate, and returns it as re-enumerated (usually
+/// under a new path). With `serial`, only that controller counts.
+///
+/// hidapi has no hotplug notifications, so this polls the device list.
+/// Waiting for the device to disappear first means a controller that hasn't
+/// restarted yet isn't mistaken for one that already came back.
+pub fn wait_for_reconnect(
+    vid: u16,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    timeout: Duration,
+) -> Result<DeviceSummary> {
+    let started = Instant::now();
+    let mut seen_gone = false;
+    loop {
+        let mut devices = DualSenseHid::list(vid, pid)?;
+        if let Some(serial) = serial.filter(|s| !s.is_empty()) {
+            devices.retain(|d| d.serial.eq_ignore_ascii_case(serial));
+        }
+        match devices.into_iter().next() {
+            Some(device) if seen_gone => {
+                log::debug!(
+                    "Controller back at {} after {}ms",
+                    device.path,
+                    started.elapsed().as_millis()
+                );
+                return Ok(device);
+            }
+            Some(_) => {}
+            None if !seen_gone => {
+                log::debug!("Controller gone after {}ms", started.elapsed().as_millis());
+                seen_gone = true;
+            }
+            None => {}
+        }
+        if started.elapsed() >= timeout {
+            return Err(AppError::ReconnectTimeout {
+                secs: timeout.as_secs(),
+            });
+        }
+        std::thread::sleep(RECONNECT_POLL_INTERVAL);
+    }
+}
+
+/// Returns the first entry of `KNOWN_DEVICES` that has a device attached.
+pub fn detect_known_device() -> Result<(u16, u16, &'static str)> {
     let api = HidApi::new()?;
-    let device = api
-        .device_list()
-        .find(|d| d.vendor_id() == vid && d.product_id() == pid)
-        .ok_or(AppError::DeviceNotFound { vid, pid })?;
-    Ok(device.path().to_string_lossy().to_string())
+    KNOWN_DEVICES
+        .iter()
+        .copied()
+        .find(|(vid, pid, _)| {
+            api.device_list()
+                .any(|d| d.vendor_id() == *vid && d.product_id() == *pid)
+        })
+        .ok_or(AppError::NoKnownDeviceFound)
 }
 
 impl DualSenseHid {
+    /// Lists devices with the given VID, and PID unless `pid` is `None`,
+    /// without opening any of them. Sorted by serial number, then path.
+    pub fn list(vid: u16, pid: Option<u16>) -> Result<Vec<DeviceSummary>> {
+        let api = HidApi::new()?;
+        let devices = matching_sorted(&api, vid, pid)
+            .into_iter()
+            .map(DeviceSummary::from_info)
+            .collect();
+        Ok(devices)
+    }
+
     pub fn open(vid: u16, pid: u16, path: Option<&str>) -> Result<Self> {
         let api = HidApi::new()?;
-        let dev = if let Some(path_str) = path {
-            if let Ok(path) = CString::new(path_str) {
-                api.open_path(&path)?
-            } else {
-                let device_path = find_path(&api, vid, pid, path_str)?;
-                api.open_path(device_path)?
-            }
+        let locking = DEVICE_LOCKING.load(Ordering::Relaxed);
+        let (dev, lock) = if let Some(path_str) = path {
+            let lock = locking.then(|| DeviceLock::acquire(path_str)).transpose()?;
+            let dev = match find_path(&api, vid, pid, path_str) {
+                Ok(device_path) => api.open_path(device_path)?,
+                Err(err) => match CString::new(path_str) {
+                    Ok(path) => api.open_path(&path)?,
+                    Err(_) => return Err(err),
+                },
+            };
+            (dev, lock)
         } else {
             list_devices(&api, vid, pid);
-            let mut iter = api
-                .device_list()
-                .filter(|d| d.vendor_id() == vid && d.product_id() == pid);
-            let device = iter
+            let device = matching_sorted(&api, vid, Some(pid))
+                .into_iter()
                 .next()
                 .ok_or(AppError::DeviceNotFound { vid, pid })?;
-            device.open_device(&api)?
+            let device_path = device.path().to_string_lossy();
+            let lock = locking.then(|| DeviceLock::acquire(&device_path)).transpose()?;
+            (device.open_device(&api)?, lock)
         };
-        Ok(Self { _api: api, dev })
+        let mut hid = Self::from_device(dev);
+        hid._lock = lock;
+        Ok(hid)
     }
 
-    pub fn get_firmware_info(&self) -> Result<FirmwareInfo> {
-        let raw = self.get_feature_report(REPORT_ID_FIRMWARE_INFO, 64)?;
-        if raw.len() < 20 {
-            return Err(AppError::FirmwareInfoTooShort(raw.len()));
+    /// Wraps a device the caller already opened, e.g. from its own
+    /// enumeration with a shared `HidApi`, instead of opening it again.
+    pub fn from_device(dev: HidDevice) -> Self {
+        let max_update_payload = read_max_update_payload(&dev);
+        Self {
+            dev,
+            max_update_payload,
+            inter_packet_delay: Duration::ZERO,
+            _lock: None,
         }
-        let payload = if raw.len() > 64 && raw[0] == REPORT_ID_FIRMWARE_INFO {
-            &raw[1..]
-        } else {
-            raw.as_slice()
-        };
-        if payload.len() < 47 {
-            return Err(AppError::FirmwareInfoPayloadTooShort(payload.len()));
+    }
+
+    /// Sleeps this long between the reports of one update command, for hosts
+    /// that send them faster than the controller takes them.
+    pub fn with_inter_packet_delay(mut self, delay: Duration) -> Self {
+        self.inter_packet_delay = delay;
+        self
+    }
+
+    pub fn serial_number(&self) -> Result<Option<String>> {
+        Ok(self.dev.get_serial_number_string()?)
+    }
+
+    /// Reads one input report and decodes the battery state from it. Returns
+    /// `None` if no report arrives in time or it isn't a USB input report.
+    pub fn read_battery(&self) -> Result<Option<BatteryStatus>> {
+        let mut buf = [0u8; 64];
+        let size = self.dev.read_timeout(&mut buf, 1000)?;
+        log::debug!("Input report: {} bytes, id=0x{:02x}", size, buf[0]);
+        Ok(BatteryStatus::parse(&buf[..size]))
+    }
+
+    /// Meant to soft-reset the controller, e.g. to get it out of a stuck
+    /// update without re-plugging. No reset or reboot feature report is known
+    /// for the DualSense (FinalizeUpdate restarts it, but only to commit a
+    /// verified image), so this fails instead of sending a guessed report.
+    pub fn reset(&self) -> Result<()> {
+        Err(AppError::ResetNotSupported)
+    }
+
+    /// Reads feature report `report_id`, asking for `length` bytes including
+    /// the report ID, and returns what the device sent.
+    pub fn get_feature_report(&self, report_id: u8, length: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; length];
+        if !buf.is_empty() {
+            buf[0] = report_id;
         }
-        let build_date = decode_ascii(&payload[..12]);
-        let build_time = decode_ascii(&payload[12..20]);
-        let firmware_version = u16::from_le_bytes([payload[44], payload[45]]);
-        let unknown = payload[20..].to_vec();
-        Ok(FirmwareInfo {
-            build_date,
-            build_time,
-            firmware_version,
-            unknown,
-            raw,
-        })
+        let size = self.dev.get_feature_report(&mut buf)?;
+        if size == 0 {
+            return Err(AppError::ShortFeatureReport {
+                report_id,
+                got: 0,
+                expected: length,
+            });
+        }
+        buf.truncate(size);
+        Ok(buf)
+    }
+
+    fn send_feature_report_raw(&self, data: &[u8]) -> Result<()> {
+        self.dev.send_feature_report(data)?;
+        Ok(())
+    }
+}
+
+impl HidTransport for DualSenseHid {
+    fn max_update_payload(&self) -> usize {
+        self.max_update_payload
     }
 
-    pub fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
-        let max_chunk = 0x39usize;
+    fn get_firmware_info(&self) -> Result<FirmwareInfo> {
+        let mut raw = self.get_feature_report(REPORT_ID_FIRMWARE_INFO, 64)?;
+        if raw.len() < FIRMWARE_INFO_MIN_LEN {
+            log::debug!("Firmware info report short: {} bytes, retrying", raw.len());
+            raw = self.get_feature_report(REPORT_ID_FIRMWARE_INFO, 64)?;
+            log::debug!("Firmware info report retry: {} bytes", raw.len());
+            if raw.len() < FIRMWARE_INFO_MIN_LEN {
+                return Err(AppError::ShortFeatureReport {
+                    report_id: REPORT_ID_FIRMWARE_INFO,
+                    got: raw.len(),
+                    expected: FIRMWARE_INFO_MIN_LEN,
+                });
+            }
+        }
+        log::trace!("firmware info (0x20) report: {}", hexdump(&raw));
+        FirmwareInfo::parse(raw)
+    }
+
+    fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
+        let max_chunk = self.max_update_payload;
         let offsets: Vec<usize> = if payload.is_empty() {
             vec![0]
         } else {
             (0..payload.len()).step_by(max_chunk).collect()
         };
         for off in offsets {
+            if off > 0 && !self.inter_packet_delay.is_zero() {
+                std::thread::sleep(self.inter_packet_delay);
+            }
             let chunk = &payload[off..payload.len().min(off + max_chunk)];
             let data_len = chunk.len() as u8;
             let data = [REPORT_ID_UPDATE_COMMAND, command as u8, data_len]
@@ -90,69 +323,94 @@ impl DualSenseHid {
                 .take(4)
                 .map(|b| format!("{:02x}", b))
                 .collect::<Vec<_>>()
-                .join(" ");
-            log::debug!("F4 chunk off={} len={} first4={}", off, chunk.len(), preview);
+                .join(",");
+            // Running total, so a failing transfer can be placed in the payload.
+            let sent = format!("{}/{}", off + chunk.len(), payload.len());
+            log::debug!(
+                phase = command.phase(), off = off, len = chunk.len(), sent = sent.as_str(),
+                first4 = preview.as_str();
+                "F4 chunk"
+            );
+            log::trace!("F4 report: {}", hexdump(&data));
             self.send_feature_report_raw(&data)?;
         }
         Ok(())
     }
 
-    pub fn get_update_status(&self, length: usize) -> Result<UpdateStatus> {
+    fn get_update_status(&self, length: usize) -> Result<UpdateStatus> {
         let raw = self.get_feature_report(REPORT_ID_UPDATE_STATUS, length)?;
         let dump = raw
             .iter()
             .map(|b| format!("{:02x}", b))
             .collect::<Vec<_>>()
-            .join(" ");
-        log::debug!("F5 status raw: {}", dump);
-        if raw.is_empty() {
-            return Err(AppError::UpdateStatusEmpty);
-        }
-        if raw[0] != REPORT_ID_UPDATE_STATUS || raw.len() != 4 {
-            return Err(AppError::UpdateStatusMalformed(raw.len()));
-        }
-        let command = UpdateCommand::from_int(raw[1]);
-        Ok(UpdateStatus {
-            report_id: raw[0],
-            command,
-            status_raw: raw[2],
-            raw,
-        })
+            .join(",");
+        log::debug!(raw = dump.as_str(); "F5 status");
+        UpdateStatus::parse(&raw)
     }
+}
 
-    fn get_feature_report(&self, report_id: u8, length: usize) -> Result<Vec<u8>> {
-        let mut buf = vec![0u8; length];
-        if !buf.is_empty() {
-            buf[0] = report_id;
+/// Space-separated hex of a whole report, for `-vvv` traces.
+fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Sizes update command payloads from the 0xF4 feature report in the
+/// device's report descriptor, capped at `MAX_UPDATE_PAYLOAD`.
+fn read_max_update_payload(dev: &HidDevice) -> usize {
+    let mut buf = vec![0u8; MAX_REPORT_DESCRIPTOR_SIZE];
+    let declared = match dev.get_report_descriptor(&mut buf) {
+        Ok(size) => feature_report_len(&buf[..size], REPORT_ID_UPDATE_COMMAND),
+        Err(err) => {
+            log::debug!("Report descriptor unavailable: {err}");
+            None
         }
-        let size = self.dev.get_feature_report(&mut buf)?;
-        buf.truncate(size);
-        Ok(buf)
+    };
+    // The payload follows the command and length bytes. Only ever shrink it:
+    // 0x39 is the size known to work, even if the report has room for more.
+    match declared.and_then(|len| len.checked_sub(2)) {
+        Some(payload) if payload > 0 && payload < MAX_UPDATE_PAYLOAD => {
+            log::debug!("Device declares {payload}-byte update payloads");
+            payload
+        }
+        _ => MAX_UPDATE_PAYLOAD,
     }
+}
 
-    fn send_feature_report_raw(&self, data: &[u8]) -> Result<()> {
-        self.dev.send_feature_report(data)?;
-        Ok(())
-    }
+/// The devices with the given VID (and PID unless `None`), sorted by serial
+/// number and then path. hidapi's own order comes from the OS and isn't
+/// stable across runs, so without this "the first controller" could be a
+/// different one each time when two are attached.
+fn matching_sorted(api: &HidApi, vid: u16, pid: Option<u16>) -> Vec<&DeviceInfo> {
+    let mut devices: Vec<_> = api
+        .device_list()
+        .filter(|d| d.vendor_id() == vid && pid.is_none_or(|pid| d.product_id() == pid))
+        .collect();
+    devices.sort_by_key(|d| (d.serial_number().unwrap_or(""), d.path()));
+    devices
 }
 
 fn list_devices(api: &HidApi, vid: u16, pid: u16) {
     let mut found = false;
-    for (idx, device) in api
-        .device_list()
-        .filter(|d| d.vendor_id() == vid && d.product_id() == pid)
+    for (idx, device) in matching_sorted(api, vid, Some(pid))
+        .into_iter()
+        .map(DeviceSummary::from_info)
         .enumerate()
     {
         found = true;
-        let path = device.path();
-        let usage_page = device.usage_page();
-        let usage = device.usage();
-        let iface = device.interface_number();
-        let product = device.product_string().unwrap_or("");
-        let serial = device.serial_number().unwrap_or("");
         log::debug!(
-            "[{}] path={:?} iface={} usage_page=0x{:04x} usage=0x{:04x} product={:?} serial={:?}",
-            idx, path, iface, usage_page, usage, product, serial
+            "[{}] model={:?} path={:?} iface={} usage_page=0x{:04x} usage=0x{:04x} product={:?} serial={:?}",
+            idx,
+            Model::from_ids(vid, pid).name(),
+            device.path,
+            device.interface,
+            device.usage_page,
+            device.usage,
+            device.product,
+            device.serial
         );
     }
     if !found {
@@ -168,11 +426,51 @@ fn find_path<'a>(api: &'a HidApi, vid: u16, pid: u16, path_str: &str) -> Result<
     let mut matches = api
         .device_list()
         .filter(|d| d.vendor_id() == vid && d.product_id() == pid);
+    let wanted = normalize_path(path_str);
     for device in matches.by_ref() {
         let path = device.path();
-        if path.to_string_lossy() == path_str {
+        if normalize_path(&path.to_string_lossy()) == wanted {
             return Ok(path);
         }
     }
     Err(AppError::DevicePathNotMatched(path_str.to_string()))
 }
+
+/// Canonical form of a HID path for comparison. Windows device interface
+/// paths (`\\?\hid#vid_054c&pid_0ce6#...`) are case-insensitive, so they're
+/// compared lowercased; hidraw (`/dev/hidraw3`) and macOS
+/// (`DevSrvsID:4294971234`) paths are compared exactly.
+fn normalize_path(path: &str) -> String {
+    let path = path.trim();
+    if cfg!(windows) {
+        path.to_lowercase()
+    } else {
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(windows)]
+    fn normalize_path_folds_case_on_windows() {
+        assert_eq!(
+            normalize_path(r"\\?\HID#VID_054C&PID_0CE6#7&1a2b3c&0&0000"),
+            normalize_path(r"\\?\hid#vid_054c&pid_0ce6#7&1A2B3C&0&0000"),
+        );
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn normalize_path_keeps_case_elsewhere() {
+        assert_eq!(normalize_path("/dev/hidraw3"), "/dev/hidraw3");
+        assert_ne!(normalize_path("/dev/HIDRAW3"), normalize_path("/dev/hidraw3"));
+    }
+
+    #[test]
+    fn normalize_path_trims_whitespace() {
+        assert_eq!(normalize_path(" /dev/hidraw3\n"), normalize_path("/dev/hidraw3"));
+    }
+}
@@ -0,0 +1,50 @@
+//! Reading firmware images from disk, including gzip-compressed ones as some
+//! redistributed downloads are. A file counts as compressed if it has the gzip
+//! magic bytes or a `.gz` extension; decompressing it needs the `gzip`
+//! feature. Either way the rest of the tool only ever sees the raw image.
+
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn read_image_file(path: &Path) -> Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    let has_gz_extension = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+    if !data.starts_with(&GZIP_MAGIC) && !has_gz_extension {
+        return Ok(data);
+    }
+    let image = decompress(&data)?;
+    log::debug!("Decompressed {} to {} bytes", path.display(), image.len());
+    Ok(image)
+}
+
+#[cfg(feature = "gzip")]
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    use crate::protocol::MAX_IMAGE_LEN;
+
+    // Stop one byte past the largest plausible image, so a bogus or
+    // malicious file can't expand without bound; validation rejects it.
+    let mut image = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .take(MAX_IMAGE_LEN as u64 + 1)
+        .read_to_end(&mut image)
+        .map_err(|err| AppError::ImageDecompressFailed(err.to_string()))?;
+    if image.is_empty() {
+        return Err(AppError::ImageDecompressFailed("it decompressed to nothing".to_string()));
+    }
+    Ok(image)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn decompress(_data: &[u8]) -> Result<Vec<u8>> {
+    Err(AppError::ImageDecompressFailed(
+        "this build has no gzip support; rebuild with --features gzip or decompress it first"
+            .to_string(),
+    ))
+}
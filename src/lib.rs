@@ -0,0 +1,15 @@
+//! The update protocol and transports behind the `dualsense-updater` CLI, for
+//! front-ends that drive an update themselves. `update::DualSenseUpdater` is
+//! the blocking API; the `async` feature adds `update_async`.
+
+pub mod digest;
+pub mod error;
+pub mod hid;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock_hid;
+pub mod output;
+pub mod progress;
+pub mod protocol;
+pub mod update;
+#[cfg(feature = "async")]
+pub mod update_async;
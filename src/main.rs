@@ -1,26 +1,58 @@
 mod cli;
-mod hid;
-mod error;
-mod protocol;
-mod update;
+mod config;
+mod image_file;
+mod event_log;
+mod replay;
+mod signature;
+#[cfg(feature = "tui")]
+mod tui;
 
-use clap::{CommandFactory, Parser};
+use dualsense_updater::{error, hid, output, progress, protocol, update};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+use clap::CommandFactory;
 use log::LevelFilter;
 
-use crate::cli::Args;
+use serde::Serialize;
+
+use crate::cli::{Args, LogFormat, OutputFormat};
+use crate::config::Config;
 use crate::error::{
-    AppError, FinalizeUpdateError, Result, StartUpdateError, UpdateFailure,
+    AppError, FinalizeUpdateError, HidErrno, Result, StartUpdateError, UpdateFailure,
     VerifyUpdateImageError, WriteUpdateImageError,
 };
-use crate::hid::{find_first_device_path, DualSenseHid};
-use crate::update::DualSenseUpdater;
+use crate::event_log::EventLog;
+use crate::hid::{
+    detect_known_device, wait_for_reconnect, DeviceSummary, DualSenseHid, HidTransport,
+};
+use crate::image_file::read_image_file;
+use crate::progress::{ConsoleProgress, JsonProgress, ProgressSink, QuietProgress};
+use crate::protocol::{
+    format_version, ChargingState, FirmwareInfo, ImageHeader, Model, VersionField,
+    MAX_IMAGE_LEN, REPORT_ID_FIRMWARE_INFO, REPORT_ID_UPDATE_STATUS, WRITE_CHUNK_LEN,
+};
+use crate::replay::{RecordingHid, ReplayHid};
+use crate::signature::verify_image_signature;
+use crate::update::{DualSenseUpdater, WriteSummary};
+
+/// Set by the first Ctrl-C during a flash; the updater stops at the next
+/// chunk or poll.
+static CANCEL: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+/// Whether a Ctrl-C should cancel gracefully instead of exiting right away.
+static FLASHING: AtomicBool = AtomicBool::new(false);
+/// `--event-log`, opened once per run so retries and `--repeat` devices all
+/// land in the same file.
+static EVENT_LOG: OnceLock<Arc<EventLog>> = OnceLock::new();
 
 fn main() {
     if std::env::args().len() == 1 {
         print_help();
         return;
     }
-    let args = match Args::try_parse() {
+    let args = match parse_args() {
         Ok(args) => args,
         Err(err) => {
             use clap::error::ErrorKind;
@@ -28,6 +60,10 @@ fn main() {
                 ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
                     println!("{err}");
                 }
+                ErrorKind::Io => {
+                    eprint!("{err}");
+                    std::process::exit(1);
+                }
                 _ => {
                     print_help();
                 }
@@ -35,104 +71,1492 @@ fn main() {
             return;
         }
     };
-    init_logging(args.verbose);
+    output::init(args.no_color, args.format == OutputFormat::Json);
+    hid::set_device_locking(!args.no_device_lock);
+    install_ctrlc_handler();
+    if let Err(err) = init_logging(args.verbose, args.log_file.as_deref(), args.log_format) {
+        output::error(format_error(&err));
+        std::process::exit(exit_code(&err));
+    }
+    if let Some(path) = &args.config {
+        log::info!("Using config file {}", path.display());
+    }
+    let format = args.format;
     if let Err(err) = run(args) {
-        println!("{}", format_error(&err));
-        std::process::exit(1);
+        report_error(&err, format);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+/// Prints a failure: as a JSON object on stdout with `--format json`,
+/// otherwise as the usual message.
+fn report_error(err: &AppError, format: OutputFormat) {
+    match format {
+        OutputFormat::Json => match serde_json::to_string(err) {
+            Ok(json) => output::json(json),
+            Err(_) => output::error(format_error(err)),
+        },
+        OutputFormat::Text => output::error(format_error(err)),
     }
 }
 
-fn run(args: Args) -> Result<()> {
-    if (args.start_update || args.write_update_image) && args.fw_image.is_empty() {
+fn run(mut args: Args) -> Result<()> {
+    if args.list_devices {
+        return list_devices(&args);
+    }
+
+    if let Some(secs) = args.wait_for_device
+        && !args.inspect
+        && args.list_images.is_none()
+        && args.diff_info.is_empty()
+        && args.compare_image.is_empty()
+    {
+        wait_for_device(&args, Duration::from_secs(secs))?;
+    }
+
+    if args.auto_detect {
+        let (vid, pid, name) = detect_known_device()?;
+        if args.format == OutputFormat::Text {
+            output::info(format!("Detected {} ({:04x}:{:04x})", name, vid, pid));
+        }
+        args.vid = vid;
+        args.pid = pid;
+    }
+
+    if (args.start_update || args.write_update_image || args.inspect) && args.fw_image.is_empty() {
         return Err(AppError::MissingFirmwareImageForUpdate);
     }
 
+    if args.inspect {
+        let image = load_image(&args)?;
+        return inspect_image(&image, version_field(&args), args.format);
+    }
+
+    if let Some(dir) = &args.list_images {
+        return list_images(dir, version_field(&args), args.format);
+    }
+
+    if let [a, b] = args.diff_info.as_slice() {
+        return diff_info(a, b);
+    }
+
+    if let [a, b] = args.compare_image.as_slice() {
+        return compare_images(a, b, version_field(&args));
+    }
+
     let has_action = args.print_firmware_info
+        || args.dump_firmware_info
+        || args.save_info.is_some()
+        || args.print_report.is_some()
+        || args.reset_device
+        || args.abort_update
+        || args.probe
         || args.start_update
         || args.write_update_image
         || args.verify_update_image
-        || args.finalize_update;
+        || args.finalize_update
+        || args.watch_status;
 
     if !has_action {
         if args.fw_image.is_empty() {
             return Err(AppError::MissingFirmwareImageForInteractive);
         }
-        println!("USE AT YOUR OWN RISK! There is no guarantee this won't brick your controller - but it probably won't.");
-        let device_path = find_first_device_path(args.vid, args.pid)?;
-        println!("Controller detected ({})", device_path);
-        let dev = DualSenseHid::open(args.vid, args.pid, Some(device_path.as_str()))?;
-        let updater = DualSenseUpdater::new(dev);
-
-        let info = updater.read_firmware_info()?;
-        println!("Current firmware version: 0x{:04x}", info.firmware_version);
-
-        let image_path = std::path::Path::new(&args.fw_image);
-        let target_version = DualSenseUpdater::firmware_version_from_image(image_path)?;
-        if prompt_yes_no(&format!(
-            "Do you want to flash the device to firmware version 0x{:04x}?",
-            target_version
-        ))? {
-            updater.start_update(image_path)?;
-            println!("StartUpdate status: SUCCESS (0x00)");
-            updater.write_update_image(image_path)?;
-            updater.verify_update_image()?;
-            println!("VerifyUpdate status: SUCCESS (0x00)");
-            updater.finalize_update()?;
-            println!("FinalizeUpdate sent");
+        let image = load_image(&args)?;
+        DualSenseUpdater::validate_image(&image, version_field(&args))?;
+        output::warn("USE AT YOUR OWN RISK! There is no guarantee this won't brick your controller - but it probably won't.");
+        if let Some(path) = &args.event_log {
+            let _ = EVENT_LOG.set(EventLog::create(path)?);
+        }
+        if !args.repeat {
+            flash_with_retries(&args, &image, true)?;
+            return Ok(());
+        }
+        let mut succeeded = 0;
+        let mut failed = 0;
+        let mut skipped = 0;
+        let mut confirm = true;
+        loop {
+            match flash_with_retries(&args, &image, confirm) {
+                Ok(FlashOutcome::Flashed) => {
+                    succeeded += 1;
+                    confirm = false;
+                }
+                Ok(FlashOutcome::AlreadyCurrent) => skipped += 1,
+                Ok(FlashOutcome::VerifyFailed) => {
+                    failed += 1;
+                    confirm = false;
+                }
+                Ok(FlashOutcome::Declined) => break,
+                Err(err) => {
+                    report_error(&err, args.format);
+                    failed += 1;
+                }
+            }
+            output::info(format!(
+                "Controllers so far: {} succeeded, {} failed, {} skipped",
+                succeeded, failed, skipped
+            ));
+            if !wait_for_enter("Plug in the next controller and press Enter (or Ctrl-C to quit)")? {
+                break;
+            }
         }
         return Ok(());
     }
 
-    let device_path = if args.path.is_empty() {
-        let found = find_first_device_path(args.vid, args.pid)?;
-        println!("Device path: {}", found);
-        Some(found)
-    } else {
-        println!("Device path: {}", args.path);
-        Some(args.path)
-    };
-    let dev = DualSenseHid::open(args.vid, args.pid, device_path.as_deref())?;
-    let updater = DualSenseUpdater::new(dev);
+    let json = args.format == OutputFormat::Json;
+    let device_path = select_device_path(&args)?;
+    if !json {
+        output::info(format!("Device path: {}", device_path));
+    }
+    let dev = DualSenseHid::open(args.vid, args.pid, Some(device_path.as_str()))?
+        .with_inter_packet_delay(Duration::from_micros(args.inter_packet_delay_us));
+    if let Some(expected) = &args.confirm_serial {
+        confirm_serial(&dev, expected)?;
+    }
+    if let Some(report_id) = args.print_report {
+        // Only ever a GET_REPORT: nothing is sent to the device.
+        let raw = dev.get_feature_report(report_id, args.length)?;
+        output::info(format!("Feature report 0x{:02x}: {} bytes", report_id, raw.len()));
+        output::info(output::hexdump(&raw));
+        return Ok(());
+    }
+    if args.probe {
+        return probe(&dev, args.format);
+    }
+    if args.reset_device {
+        dev.reset()?;
+        output::success("Controller reset");
+        return Ok(());
+    }
+    let serial_number = dev.serial_number()?;
+    let updater = DualSenseUpdater::with_cancel_token(dev, CANCEL.clone())
+        .with_progress(progress_sink(&args, 0))
+        .with_poll_interval(Duration::from_millis(args.poll_interval_ms))
+        .with_version_field(version_field(&args))
+        .with_inter_chunk_delay(Duration::from_micros(args.inter_packet_delay_us))
+        .with_verify_timeout(Duration::from_secs(args.verify_timeout_secs));
 
+    if args.abort_update {
+        if !json {
+            output::warn(
+                "There is no command to abort an update. After StartUpdate the controller \
+                 stays mid-update until an image is written, verified and finalized; to \
+                 recover, run a complete update with a valid image for this model.",
+            );
+        }
+        return updater.abort_update();
+    }
     if args.print_firmware_info {
         let info = updater.read_firmware_info()?;
-        println!("Current firmware build date: {}", info.build_date);
-        println!("Current firmware build time: {}", info.build_time);
-        println!("Current firmware version: 0x{:04x}", info.firmware_version);
+        if json {
+            let report = FirmwareInfoReport {
+                info: &info,
+                serial_number,
+            };
+            output::json(serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?);
+        } else {
+            output::info(format!("Current firmware build date: {}", info.build_date));
+            output::info(format!("Current firmware build time: {}", info.build_time));
+            output::info(format!("Current firmware version: {}", info.version_string()));
+            output::info(format!("hw_id: 0x{:08x}", info.hw_id));
+        }
+    }
+
+    if args.dump_firmware_info {
+        let info = updater.read_firmware_info()?;
+        output::info(output::hexdump(&info.raw));
+        for (name, range) in info.known_fields() {
+            output::info(format!("0x{:02x}..0x{:02x}  {}", range.start, range.end, name));
+        }
+    }
+
+    if let Some(save_path) = &args.save_info {
+        let info = updater.read_firmware_info()?;
+        std::fs::write(save_path, &info.raw)?;
+        output::success(format!(
+            "Saved {} bytes of firmware info to {}",
+            info.raw.len(),
+            save_path.display()
+        ));
     }
 
+    let flashing = FlashGuard::start();
+    // The *-only steps always run in protocol order (start, write, verify,
+    // finalize) whatever order the flags were given in, and stop at the first
+    // failure, so they can be combined, e.g. write + verify.
+    let image = if args.start_update || args.write_update_image {
+        load_image(&args)?
+    } else {
+        Vec::new()
+    };
+
     if args.start_update {
-        let image_path = std::path::Path::new(&args.fw_image);
-        updater.start_update(image_path)?;
-        println!("StartUpdate status: SUCCESS");
+        DualSenseUpdater::validate_image(&image, version_field(&args))?;
+        let header = updater.start_update(&image)?;
+        output::success(format!(
+            "StartUpdate status: SUCCESS (target firmware version {})",
+            format_version(header.firmware_version)
+        ));
     }
 
     if args.write_update_image {
-        let image_path = std::path::Path::new(&args.fw_image);
-        updater.write_update_image(image_path)?;
+        if args.resume_from > 0 {
+            output::warn(format!(
+                "Resuming WriteUpdateImage at chunk {} is experimental and only valid if StartUpdate and the earlier chunks already succeeded.",
+                args.resume_from
+            ));
+        }
+        let written = updater.write_update_image_from(&image, args.resume_from)?;
+        output::info(format!(
+            "Wrote {} chunks ({} bytes) in {:.2}s",
+            written.chunks_written,
+            written.bytes_written,
+            written.elapsed.as_secs_f64()
+        ));
+        output::info(format!("Written image digest: {}", written.digest));
     }
 
     if args.verify_update_image {
         updater.verify_update_image()?;
-        println!("VerifyUpdate status: SUCCESS");
+        output::success("VerifyUpdate status: SUCCESS");
     }
 
     if args.finalize_update {
-        updater.finalize_update()?;
-        println!("FinalizeUpdate sent");
+        output::warn("WARNING: --finalize-update-only bypasses the normal start/write/verify sequence.");
+        if args.force {
+            updater.finalize_update_unchecked()?;
+        } else {
+            updater.finalize_update()?;
+        }
+        output::success("FinalizeUpdate sent");
+    }
+    // Stopping after whichever steps were asked for is the point of them.
+    updater.release();
+    drop(flashing);
+
+    if args.watch_status {
+        watch_status(&updater, args.interval)?;
     }
 
     Ok(())
 }
 
-fn init_logging(debug: bool) {
-    let mut builder = env_logger::Builder::from_default_env();
-    if debug {
-        builder.filter_level(LevelFilter::Debug);
+/// Reads FW_IMAGE, dropping the first `--image-offset` bytes so every phase
+/// sees the image as starting with its header.
+fn load_image(args: &Args) -> Result<Vec<u8>> {
+    let mut image = read_image_file(std::path::Path::new(&args.fw_image))?;
+    if args.image_offset > image.len() {
+        return Err(AppError::ImageOffsetOutOfRange {
+            offset: args.image_offset,
+            len: image.len(),
+        });
+    }
+    image.drain(..args.image_offset);
+    if let (Some(signature), Some(public_key)) = (&args.verify_signature, &args.pubkey) {
+        verify_image_signature(&image, signature, public_key)?;
+    }
+    Ok(image)
+}
+
+fn inspect_image(image: &[u8], field: VersionField, format: OutputFormat) -> Result<()> {
+    let file_size = image.len();
+    let version = DualSenseUpdater::firmware_version_from_image(image, field)?;
+    let (header, problem) = match DualSenseUpdater::validate_image(image, field) {
+        Ok(header) => (Some(header), None),
+        Err(
+            err @ (AppError::FirmwareImageTooSmallForHeader
+            | AppError::FirmwareImageImplausibleSize { .. }
+            | AppError::FirmwareImageMalformed(_)
+            | AppError::ImageVersionImplausible(_)),
+        ) => (None, Some(err.to_string())),
+        Err(err) => return Err(err),
+    };
+    let version = header.as_ref().map_or(version, |h| h.firmware_version);
+    if format == OutputFormat::Json {
+        let value = serde_json::json!({
+            "file_size": file_size,
+            "firmware_version": version,
+            "firmware_version_hex": format_version(version),
+            "body_size": header.as_ref().map(|h| h.body_len),
+            "header_valid": problem.is_none(),
+            "problem": problem,
+        });
+        output::json(value);
+        return Ok(());
+    }
+    output::info(format!("File size: {} bytes", file_size));
+    output::info(format!("Firmware version: {}", format_version(version)));
+    if let Some(header) = &header {
+        output::info(format!("Body size: {} bytes", header.body_len));
+    }
+    match problem {
+        None => output::success("Header: looks valid"),
+        Some(problem) => output::warn(format!("Header: {}", problem)),
+    }
+    Ok(())
+}
+
+/// `--probe`: reads the firmware info and update status reports once each
+/// and times them. Both are GET_REPORTs; the write direction isn't tested
+/// because the only output reports known are update commands, and none of
+/// those is harmless to send.
+fn probe(dev: &DualSenseHid, format: OutputFormat) -> Result<()> {
+    let started = Instant::now();
+    let info_raw = dev.get_feature_report(REPORT_ID_FIRMWARE_INFO, 64)?;
+    let info_latency = started.elapsed();
+    let started = Instant::now();
+    let status_raw = dev.get_feature_report(REPORT_ID_UPDATE_STATUS, 4)?;
+    let status_latency = started.elapsed();
+    // A blank report (recovery mode) still shows the transfer works.
+    let version = FirmwareInfo::parse(info_raw.clone()).ok().map(|info| info.firmware_version);
+    let hex = |raw: &[u8]| raw.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    if format == OutputFormat::Json {
+        output::json(serde_json::json!({
+            "firmware_info_bytes": info_raw.len(),
+            "firmware_info_ms": info_latency.as_secs_f64() * 1000.0,
+            "firmware_version": version,
+            "firmware_version_hex": version.map(format_version),
+            "update_status_raw": hex(&status_raw),
+            "update_status_ms": status_latency.as_secs_f64() * 1000.0,
+            "write_tested": false,
+        }));
+        return Ok(());
+    }
+    output::info(format!(
+        "Firmware info (0x{:02x}): {} bytes in {:.1}ms, version {}",
+        REPORT_ID_FIRMWARE_INFO,
+        info_raw.len(),
+        info_latency.as_secs_f64() * 1000.0,
+        version.map_or("unknown".to_string(), format_version)
+    ));
+    output::info(format!(
+        "Update status (0x{:02x}): {} bytes in {:.1}ms: {}",
+        REPORT_ID_UPDATE_STATUS,
+        status_raw.len(),
+        status_latency.as_secs_f64() * 1000.0,
+        hex(&status_raw)
+    ));
+    output::warn("Write direction not tested: no harmless output report is known.");
+    output::success("Feature report reads work");
+    Ok(())
+}
+
+/// `--list-devices`: every device matching `--vid`/`--pid` (any PID with
+/// `--force-pid-any`), read from the OS device list without opening any.
+fn list_devices(args: &Args) -> Result<()> {
+    let pid = if args.force_pid_any { None } else { Some(args.pid) };
+    let devices = DualSenseHid::list(args.vid, pid)?;
+    if args.format == OutputFormat::Json {
+        output::json(serde_json::json!(devices));
+        return Ok(());
+    }
+    if devices.is_empty() {
+        output::info(match pid {
+            Some(pid) => format!("No devices found for VID:PID {:04x}:{:04x}", args.vid, pid),
+            None => format!("No devices found for vendor 0x{:04x}", args.vid),
+        });
+    }
+    for (idx, device) in devices.iter().enumerate() {
+        output::info(format!(
+            "[{}] {} ({:04x}:{:04x}) serial={} interface={} usage_page=0x{:04x} usage=0x{:04x}",
+            idx + 1,
+            device.product,
+            device.vendor_id,
+            device.product_id,
+            device.serial,
+            device.interface,
+            device.usage_page,
+            device.usage
+        ));
+        output::info(format!("    path={}", device.path));
+    }
+    Ok(())
+}
+
+/// `--list-images`: every file in `dir` that passes `validate_image`, by
+/// name. Anything else is skipped, as are files too big to be an image, which
+/// aren't read at all.
+fn list_images(dir: &std::path::Path, version: VersionField, format: OutputFormat) -> Result<()> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    let mut images = Vec::new();
+    for path in paths {
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        if !metadata.is_file() || metadata.len() > MAX_IMAGE_LEN as u64 {
+            continue;
+        }
+        let image = match read_image_file(&path) {
+            Ok(image) => image,
+            Err(err) => {
+                log::debug!("Skipping {}: {err}", path.display());
+                continue;
+            }
+        };
+        match DualSenseUpdater::validate_image(&image, version) {
+            Ok(header) => images.push((path, header.firmware_version, image.len())),
+            Err(err) => log::debug!("Skipping {}: {err}", path.display()),
+        }
+    }
+    if format == OutputFormat::Json {
+        let value: Vec<_> = images
+            .iter()
+            .map(|(path, version, size)| {
+                serde_json::json!({
+                    "file": path.file_name().unwrap_or(path.as_os_str()).to_string_lossy(),
+                    "firmware_version": version,
+                    "firmware_version_hex": format_version(*version),
+                    "size": size,
+                })
+            })
+            .collect();
+        output::json(serde_json::Value::from(value));
+        return Ok(());
+    }
+    if images.is_empty() {
+        output::warn(format!("No firmware images found in {}", dir.display()));
+    }
+    for (path, version, size) in &images {
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+        output::info(format!("{}  {}  {} bytes", name, format_version(*version), size));
+    }
+    Ok(())
+}
+
+fn diff_info(a_path: &std::path::Path, b_path: &std::path::Path) -> Result<()> {
+    let a = std::fs::read(a_path)?;
+    let b = std::fs::read(b_path)?;
+    let ranges = diff_ranges(&a, &b);
+    let differs = |offset: usize| ranges.iter().any(|r| r.contains(&offset));
+    output::info(format!("{}:", a_path.display()));
+    output::info(output::hexdump_marked(&a, differs));
+    output::info(format!("{}:", b_path.display()));
+    output::info(output::hexdump_marked(&b, differs));
+    if ranges.is_empty() {
+        output::success("Reports are identical");
+        return Ok(());
+    }
+    let count: usize = ranges.iter().map(|r| r.len()).sum();
+    output::warn(format!("{} bytes differ:", count));
+    for range in &ranges {
+        output::info(format!("  0x{:02x}..0x{:02x}", range.start, range.end));
+    }
+    Ok(())
+}
+
+/// Differing ranges listed by `--compare-image` before the rest are only
+/// counted.
+const MAX_LISTED_RANGES: usize = 32;
+
+fn compare_images(
+    a_path: &std::path::Path,
+    b_path: &std::path::Path,
+    version: VersionField,
+) -> Result<()> {
+    let a = read_image_file(a_path)?;
+    let b = read_image_file(b_path)?;
+    for (path, image) in [(a_path, &a), (b_path, &b)] {
+        match ImageHeader::parse_with_version_field(image, version) {
+            Ok(header) => output::info(format!(
+                "{}: version {}, {} bytes",
+                path.display(),
+                format_version(header.firmware_version),
+                image.len()
+            )),
+            Err(err) => output::warn(format!("{}: {} ({} bytes)", path.display(), err, image.len())),
+        }
+    }
+    let ranges = diff_ranges(&a, &b);
+    if ranges.is_empty() {
+        output::success("Images are identical");
+        return Ok(());
+    }
+    let count: usize = ranges.iter().map(|r| r.len()).sum();
+    let total_chunks = a.len().max(b.len()).div_ceil(WRITE_CHUNK_LEN);
+    let mut changed_chunks: Vec<usize> = ranges
+        .iter()
+        .flat_map(|r| r.start / WRITE_CHUNK_LEN..=(r.end - 1) / WRITE_CHUNK_LEN)
+        .collect();
+    changed_chunks.dedup();
+    output::warn(format!(
+        "{} bytes differ in {} ranges, touching {} of {} 0x8000-byte chunks:",
+        count,
+        ranges.len(),
+        changed_chunks.len(),
+        total_chunks
+    ));
+    for range in ranges.iter().take(MAX_LISTED_RANGES) {
+        output::info(format!("  0x{:06x}..0x{:06x}", range.start, range.end));
+    }
+    if ranges.len() > MAX_LISTED_RANGES {
+        output::info(format!("  ... and {} more", ranges.len() - MAX_LISTED_RANGES));
+    }
+    Ok(())
+}
+
+/// Contiguous offset ranges where `a` and `b` differ. Bytes past the end of
+/// the shorter buffer count as differing.
+fn diff_ranges(a: &[u8], b: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for offset in 0..a.len().max(b.len()) {
+        if a.get(offset) == b.get(offset) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == offset => last.end += 1,
+            _ => ranges.push(offset..offset + 1),
+        }
+    }
+    ranges
+}
+
+#[derive(Serialize)]
+struct FirmwareInfoReport<'a> {
+    #[serde(flatten)]
+    info: &'a FirmwareInfo,
+    serial_number: Option<String>,
+}
+
+fn watch_status<T: HidTransport>(updater: &DualSenseUpdater<T>, interval_ms: u64) -> Result<()> {
+    let interval = std::time::Duration::from_millis(interval_ms);
+    loop {
+        match updater.read_update_status() {
+            Ok(status) => {
+                let dump = status
+                    .raw
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                output::info(format!(
+                    "command={:?} status=0x{:02x} extra=0x{:02x} raw={}",
+                    status.command, status.status_raw, status.extra, dump
+                ));
+            }
+            Err(
+                err @ (AppError::UpdateStatusEmpty
+                | AppError::UpdateStatusMalformed(_)
+                | AppError::UnknownStatusCommandByte(_)),
+            ) => {
+                output::warn(err);
+            }
+            Err(err) => return Err(err),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// `image_len` sizes the JSON progress events and the `tui` progress bar; 0
+/// when the image size isn't known up front.
+fn progress_sink(args: &Args, image_len: usize) -> Box<dyn ProgressSink + Send> {
+    if args.format == OutputFormat::Json {
+        return Box::new(JsonProgress::new(image_len));
+    }
+    if args.quiet {
+        return Box::new(QuietProgress);
+    }
+    #[cfg(feature = "tui")]
+    if args.format == OutputFormat::Text && std::io::IsTerminal::is_terminal(&std::io::stdout()) {
+        return Box::new(tui::TuiProgress::new(image_len.div_ceil(WRITE_CHUNK_LEN)));
+    }
+    Box::new(ConsoleProgress)
+}
+
+enum FlashOutcome {
+    Flashed,
+    Declined,
+    AlreadyCurrent,
+    /// The controller rejected the image in VerifyUpdateImage and
+    /// `--continue-on-verify-error` kept that from being an error.
+    VerifyFailed,
+}
+
+impl FlashOutcome {
+    fn name(&self) -> &'static str {
+        match self {
+            FlashOutcome::Flashed => "flashed",
+            FlashOutcome::Declined => "declined",
+            FlashOutcome::AlreadyCurrent => "already_current",
+            FlashOutcome::VerifyFailed => "verify_failed",
+        }
+    }
+}
+
+/// How many times a flash is restarted after the controller drops off the bus,
+/// on top of `--retry-whole-update`.
+const MAX_RECONNECTS: u32 = 3;
+/// How long to wait for a disconnected controller to come back.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `--retry-whole-update`: reruns the full sequence, from opening the device
+/// on, after failures that a fresh attempt could get past. Only the first
+/// attempt asks for confirmation.
+///
+/// A controller that disconnects mid-flash (e.g. a hub glitch) is waited for
+/// and found again by serial number, then flashed again from StartUpdate. The
+/// phase that was interrupted can't be picked up where it stopped: the status
+/// report carries no write position, and whether the controller keeps a
+/// partial image across re-enumeration isn't known.
+fn flash_with_retries(args: &Args, image: &[u8], confirm: bool) -> Result<FlashOutcome> {
+    let mut attempt = 0;
+    let mut reconnects = 0;
+    let mut pinned: Option<Args> = None;
+    loop {
+        let events = EVENT_LOG.get();
+        if let Some(log) = events {
+            let json = serde_json::json!({ "attempt": attempt + reconnects });
+            log.record("attempt_started", json);
+        }
+        let mut serial: Option<String> = None;
+        let first = attempt == 0 && reconnects == 0;
+        let current = pinned.as_ref().unwrap_or(args);
+        let result = flash_device(current, image, confirm && first, &mut serial);
+        if let Some(log) = events {
+            log.attempt_finished(result.as_ref().map(|outcome| outcome.name()));
+        }
+        match result {
+            Err(err)
+                if err.is_disconnect()
+                    && reconnects < MAX_RECONNECTS
+                    && serial.as_deref().is_some_and(|s| !s.is_empty()) =>
+            {
+                reconnects += 1;
+                output::warn(format!(
+                    "{}; waiting for the controller to come back (reconnect {} of {})",
+                    format_error(&err),
+                    reconnects,
+                    MAX_RECONNECTS
+                ));
+                let device = find_again(args, serial.as_deref().unwrap_or_default())?;
+                output::info(format!(
+                    "Controller {} is back at {}; restarting the update from StartUpdate",
+                    device.serial, device.path
+                ));
+                let mut retry = args.clone();
+                retry.path = device.path;
+                retry.serial = device.serial;
+                pinned = Some(retry);
+            }
+            Err(err) if attempt < args.retry_whole_update && err.is_retryable() => {
+                attempt += 1;
+                output::warn(format!(
+                    "{}; retrying the whole update ({} of {})",
+                    format_error(&err),
+                    attempt,
+                    args.retry_whole_update
+                ));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// `opened_serial` is set to the controller's serial number once it's open,
+/// so a caller can find the same one again after a disconnect.
+fn flash_device(
+    args: &Args,
+    image: &[u8],
+    confirm: bool,
+    opened_serial: &mut Option<String>,
+) -> Result<FlashOutcome> {
+    if let Some(replay_path) = &args.replay {
+        if !args.quiet {
+            output::info(format!("Replaying transfers from {}", replay_path.display()));
+        }
+        return flash_with(args, ReplayHid::load(replay_path)?, None, image, confirm);
+    }
+    let device_path = select_device_path(args)?;
+    if !args.quiet {
+        output::info(format!("{} detected ({})", selected_model(args).name(), device_path));
+    }
+    let dev = DualSenseHid::open(args.vid, args.pid, Some(device_path.as_str()))?
+        .with_inter_packet_delay(Duration::from_micros(args.inter_packet_delay_us));
+    if let Some(expected) = &args.confirm_serial {
+        confirm_serial(&dev, expected)?;
+    }
+    if !args.skip_battery_check {
+        check_battery(&dev, args)?;
+    }
+    let serial = dev.serial_number().ok().flatten();
+    opened_serial.clone_from(&serial);
+    let outcome = match &args.record {
+        Some(record_path) => {
+            let dev = RecordingHid::create(dev, record_path)?;
+            flash_with(args, dev, serial.as_deref(), image, confirm)?
+        }
+        None => flash_with(args, dev, serial.as_deref(), image, confirm)?,
+    };
+    if args.strict_verify && matches!(outcome, FlashOutcome::Flashed) && !args.no_finalize {
+        let expected = DualSenseUpdater::firmware_version_from_image(image, version_field(args))?;
+        check_post_update_version(args, serial.as_deref(), expected)?;
+    }
+    Ok(outcome)
+}
+
+fn version_field(args: &Args) -> VersionField {
+    VersionField {
+        offset: args.image_version_offset,
+        endian: args.version_endian,
+    }
+}
+
+fn selected_model(args: &Args) -> Model {
+    // With --force-pid-any the chosen device's PID isn't known here.
+    if args.force_pid_any {
+        Model::Unknown
     } else {
-        builder.filter_level(LevelFilter::Info);
+        Model::from_ids(args.vid, args.pid)
+    }
+}
+
+/// `--confirm-serial`: a last check, after the device is opened and before
+/// anything is sent to it, that it's the controller the caller meant.
+fn confirm_serial(dev: &DualSenseHid, expected: &str) -> Result<()> {
+    let got = dev.serial_number()?.unwrap_or_default();
+    if !got.eq_ignore_ascii_case(expected) {
+        return Err(AppError::SerialMismatch {
+            expected: expected.to_string(),
+            got: if got.is_empty() { "(none)".to_string() } else { got },
+        });
     }
-    builder.format_timestamp(None).init();
+    Ok(())
+}
+
+/// How long `--strict-verify` waits for the controller to come back after
+/// FinalizeUpdate.
+const POST_UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `--strict-verify`: waits for the controller to restart and fails unless it
+/// reports the image's version.
+fn check_post_update_version(args: &Args, serial: Option<&str>, expected: u16) -> Result<()> {
+    if !args.quiet {
+        output::info("Waiting for the controller to restart...");
+    }
+    let started = Instant::now();
+    let pid = if args.force_pid_any { None } else { Some(args.pid) };
+    // Found by serial number when it has one, since its path usually changes
+    // when it re-enumerates.
+    let device = wait_for_reconnect(args.vid, pid, serial, POST_UPDATE_TIMEOUT)?;
+    // It can take a moment after enumeration to answer feature reports.
+    let got = loop {
+        let read = DualSenseHid::open(args.vid, device.product_id, Some(device.path.as_str()))
+            .and_then(|dev| dev.get_firmware_info());
+        match read {
+            Ok(info) => break info.firmware_version,
+            Err(err) if started.elapsed() < POST_UPDATE_TIMEOUT => {
+                log::debug!("Controller not ready yet: {err}");
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    if FirmwareInfo::cmp_version(got, expected).is_ne() {
+        return Err(AppError::PostUpdateVersionMismatch { expected, got });
+    }
+    output::success(format!("Controller reports firmware version {}", format_version(got)));
+    Ok(())
+}
+
+/// Everything after the device is opened, so the same flow runs against a
+/// real controller, a recording wrapper or a replayed log. `serial` is only
+/// used for the `--checksum-file` receipt.
+fn flash_with<T: HidTransport>(
+    args: &Args,
+    dev: T,
+    serial: Option<&str>,
+    image: &[u8],
+    confirm: bool,
+) -> Result<FlashOutcome> {
+    let mut progress = progress_sink(args, image.len());
+    if let Some(log) = EVENT_LOG.get() {
+        progress = log.sink(progress);
+    }
+    let updater = DualSenseUpdater::with_cancel_token(dev, CANCEL.clone())
+        .with_progress(progress)
+        .with_poll_interval(Duration::from_millis(args.poll_interval_ms))
+        .with_version_field(version_field(args))
+        .with_inter_chunk_delay(Duration::from_micros(args.inter_packet_delay_us))
+        .with_verify_timeout(Duration::from_secs(args.verify_timeout_secs));
+
+    // A controller left in recovery mode by an interrupted update can't report
+    // its version, but flashing it again is how it gets out of that state.
+    let info = match updater.read_firmware_info() {
+        Ok(info) => Some(info),
+        Err(err @ AppError::DeviceInRecoveryMode) => {
+            output::warn(err);
+            None
+        }
+        Err(err) => return Err(err),
+    };
+    let target_version = DualSenseUpdater::firmware_version_from_image(image, version_field(args))?;
+    let change = FirmwareChange::new(info.as_ref(), target_version, selected_model(args));
+    let will_prompt = confirm && !args.yes;
+    if args.format == OutputFormat::Text && (!args.quiet || will_prompt) {
+        change.print();
+    }
+    let already_current = info.as_ref().is_some_and(|info| {
+        FirmwareInfo::cmp_version(info.firmware_version, target_version).is_eq()
+    });
+    if already_current && args.repeat {
+        output::warn(format!(
+            "Controller is already on firmware version {}, skipping",
+            format_version(target_version)
+        ));
+        return Ok(FlashOutcome::AlreadyCurrent);
+    }
+    // Re-flashing the installed version is pointless risk: without --force it
+    // needs an explicit yes, and unattended runs just report success.
+    let question = if already_current && !args.force {
+        if !confirm || args.yes {
+            output::success(format!(
+                "Device is already on {}, already up to date",
+                format_version(target_version)
+            ));
+            return Ok(FlashOutcome::AlreadyCurrent);
+        }
+        format!("Device is already on {}. Re-flash anyway?", format_version(target_version))
+    } else {
+        format!("Do you want to flash the device to firmware version {}?", format_version(target_version))
+    };
+    if will_prompt
+        && !confirm_prompt(
+            args,
+            "the flash confirmation",
+            &format!(
+                "This will write {} bytes in {} chunks. {}",
+                image.len(),
+                image.len().div_ceil(WRITE_CHUNK_LEN),
+                question
+            ),
+        )?
+    {
+        return Ok(FlashOutcome::Declined);
+    }
+    // Verify is the only check that the written image arrived intact, so
+    // skipping it needs its own answer: --yes alone isn't enough.
+    if args.no_verify && confirm && !args.force {
+        output::warn("--no-verify: VerifyUpdateImage will be skipped and the image finalized unchecked.");
+        output::warn("A corrupted write would then only show up when the controller fails to boot it.");
+        if args.headless {
+            return Err(AppError::HeadlessPrompt {
+                what: "the --no-verify confirmation",
+                answer_with: "--force",
+            });
+        }
+        if !prompt_yes_no(
+            "Skip verification anyway?",
+            args.prompt_timeout.map(Duration::from_secs),
+        )? {
+            return Ok(FlashOutcome::Declined);
+        }
+    }
+    let _flashing = FlashGuard::start();
+    let total = Instant::now();
+    let phase = Instant::now();
+    let header = updater.start_update(image)?;
+    let start_update = phase.elapsed();
+    if !args.quiet {
+        output::success("StartUpdate status: SUCCESS");
+    }
+    let write = updater.write_update_image(image)?;
+    let mut verify_error = None;
+    let verify_update_image = if args.no_verify {
+        output::warn("Skipping VerifyUpdateImage (--no-verify): the written image was not checked.");
+        None
+    } else {
+        let phase = Instant::now();
+        match updater.verify_update_image() {
+            Ok(()) => {
+                let verify_update_image = phase.elapsed();
+                if !args.quiet {
+                    output::success("VerifyUpdate status: SUCCESS");
+                }
+                Some(verify_update_image)
+            }
+            Err(err @ AppError::UpdateFailed(UpdateFailure::VerifyUpdateImage(_)))
+                if args.continue_on_verify_error =>
+            {
+                output::error(format_error(&err));
+                verify_error = Some(err);
+                None
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    let finalize_update = if verify_error.is_some() {
+        output::warn("Not finalizing an image the controller rejected (--continue-on-verify-error).");
+        updater.release();
+        None
+    } else if args.no_finalize {
+        output::warn("Image written and verified but NOT finalized — run with --finalize-update-only --force to commit.");
+        updater.release();
+        None
+    } else if confirm
+        && !args.yes
+        && !confirm_prompt(
+            args,
+            "the commit confirmation",
+            if args.no_verify {
+                "Verification was skipped. Commit the update now? This cannot be undone."
+            } else {
+                "Verification succeeded. Commit the update now? This cannot be undone."
+            },
+        )?
+    {
+        output::warn("Image written and verified but NOT finalized — run with --finalize-update-only --force to commit.");
+        updater.release();
+        return Ok(FlashOutcome::Declined);
+    } else {
+        let phase = Instant::now();
+        if args.no_verify {
+            updater.finalize_update_unchecked()?;
+        } else {
+            updater.finalize_update()?;
+        }
+        let finalize_update = phase.elapsed();
+        output::success(format!(
+            "FinalizeUpdate sent, controller will restart on firmware version {}",
+            format_version(header.firmware_version)
+        ));
+        Some(finalize_update)
+    };
+    let summary = FlashSummary {
+        change,
+        start_update,
+        write,
+        verify_update_image,
+        verify_error,
+        finalize_update,
+        total: total.elapsed(),
+    };
+    if let Some(path) = &args.checksum_file {
+        write_checksum_file(path, args, serial, header.firmware_version, &summary)?;
+    }
+    if args.format == OutputFormat::Json || !args.quiet {
+        print_flash_summary(&summary, args.format);
+    }
+    if summary.verify_error.is_some() {
+        return Ok(FlashOutcome::VerifyFailed);
+    }
+    Ok(FlashOutcome::Flashed)
+}
+
+/// What the flash would change, shown before confirming: the controller's
+/// firmware next to the image's. The image header has no known build date or
+/// model field, so those stay unknown on the image side; the controller
+/// checks the model itself at StartUpdate.
+struct FirmwareChange {
+    /// `None` in recovery mode.
+    current_version: Option<u16>,
+    current_build: Option<String>,
+    image_version: u16,
+    model: Model,
+}
+
+impl FirmwareChange {
+    fn new(info: Option<&FirmwareInfo>, image_version: u16, model: Model) -> Self {
+        Self {
+            current_version: info.map(|info| info.firmware_version),
+            current_build: info.map(|info| format!("{} {}", info.build_date, info.build_time)),
+            image_version,
+            model,
+        }
+    }
+
+    /// Going by version number; the controller refuses downgrades.
+    fn direction(&self) -> &'static str {
+        let order = self
+            .current_version
+            .map(|current| FirmwareInfo::cmp_version(self.image_version, current));
+        match order {
+            Some(std::cmp::Ordering::Greater) => "upgrade",
+            Some(std::cmp::Ordering::Less) => "downgrade",
+            Some(std::cmp::Ordering::Equal) => "same version",
+            None => "unknown",
+        }
+    }
+
+    fn print(&self) {
+        let current_version = match self.current_version {
+            Some(version) => format_version(version),
+            None => "unknown (recovery mode)".to_string(),
+        };
+        let rows = [
+            ("", "Controller".to_string(), "Image".to_string()),
+            (
+                "Version",
+                current_version,
+                format!("{} ({})", format_version(self.image_version), self.direction()),
+            ),
+            (
+                "Build date",
+                self.current_build.clone().unwrap_or_else(|| "unknown".to_string()),
+                "unknown (not in the image header)".to_string(),
+            ),
+            (
+                "Model",
+                self.model.name().to_string(),
+                "checked by the controller at StartUpdate".to_string(),
+            ),
+        ];
+        for (label, current, image) in rows {
+            output::info(format!("  {:<12} {:<24} {}", label, current, image));
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "current_version": self.current_version,
+            "current_version_hex": self.current_version.map(format_version),
+            "image_version": self.image_version,
+            "image_version_hex": format_version(self.image_version),
+            "direction": self.direction(),
+            "current_build": self.current_build,
+            "image_build": null,
+            "model": self.model.name(),
+            "model_checked_by": "StartUpdate",
+        })
+    }
+}
+
+struct FlashSummary {
+    change: FirmwareChange,
+    start_update: Duration,
+    write: WriteSummary,
+    /// `None` with `--no-verify`, or if the controller rejected the image.
+    verify_update_image: Option<Duration>,
+    /// The rejection, kept instead of failing with `--continue-on-verify-error`.
+    verify_error: Option<AppError>,
+    /// `None` with `--no-finalize`.
+    finalize_update: Option<Duration>,
+    total: Duration,
+}
+
+impl FlashSummary {
+    fn write_kib_per_sec(&self) -> f64 {
+        let secs = self.write.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.write.bytes_written as f64 / 1024.0 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+fn print_flash_summary(summary: &FlashSummary, format: OutputFormat) {
+    if format == OutputFormat::Json {
+        let value = serde_json::json!({
+            "start_update_ms": summary.start_update.as_millis(),
+            "write_update_image_ms": summary.write.elapsed.as_millis(),
+            "verify_update_image_ms": summary.verify_update_image.map(|d| d.as_millis()),
+            "verified": summary.verify_update_image.is_some(),
+            "verify_error": summary.verify_error,
+            "finalize_update_ms": summary.finalize_update.map(|d| d.as_millis()),
+            "finalized": summary.finalize_update.is_some(),
+            "total_ms": summary.total.as_millis(),
+            "image_bytes": summary.write.bytes_written,
+            "chunks_written": summary.write.chunks_written,
+            "write_retries": summary.write.retries,
+            "write_kib_per_sec": summary.write_kib_per_sec(),
+            "digest": summary.write.digest,
+            "firmware_change": summary.change.to_json(),
+        });
+        output::json(value);
+        return;
+    }
+    let verify_missing = if summary.verify_error.is_some() { "FAILED" } else { "skipped" };
+    let rows = [
+        ("StartUpdate", Some(summary.start_update), "skipped"),
+        ("WriteUpdateImage", Some(summary.write.elapsed), "skipped"),
+        ("VerifyUpdateImage", summary.verify_update_image, verify_missing),
+        ("FinalizeUpdate", summary.finalize_update, "skipped"),
+        ("Total", Some(summary.total), "skipped"),
+    ];
+    output::info("Summary:");
+    for (name, elapsed, missing) in rows {
+        match elapsed {
+            Some(elapsed) => output::info(format!("  {:<18} {:>8.2}s", name, elapsed.as_secs_f64())),
+            None => output::info(format!("  {:<18} {:>9}", name, missing)),
+        }
+    }
+    output::info(format!(
+        "  {:<18} {:>8.1} KB/s",
+        "Write throughput",
+        summary.write_kib_per_sec()
+    ));
+    output::info(format!(
+        "  {:<18} {} chunks, {} retries",
+        "Chunks written", summary.write.chunks_written, summary.write.retries
+    ));
+    output::info(format!("  {:<18} {}", "Image digest", summary.write.digest));
+}
+
+/// `--checksum-file`: a receipt of what was flashed, one `key=value` per line.
+fn write_checksum_file(
+    path: &std::path::Path,
+    args: &Args,
+    serial: Option<&str>,
+    target_version: u16,
+    summary: &FlashSummary,
+) -> Result<()> {
+    let receipt = format!(
+        "image={}\nsize={}\ndigest={}\nserial={}\ntarget_version={}\nfinalized={}\n",
+        args.fw_image,
+        summary.write.bytes_written,
+        summary.write.digest,
+        serial.filter(|s| !s.is_empty()).unwrap_or("unknown"),
+        format_version(target_version),
+        summary.finalize_update.is_some()
+    );
+    std::fs::write(path, receipt)?;
+    if !args.quiet {
+        output::info(format!("Wrote checksum file {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Polls every 500ms for the controller with `serial` until it's attached
+/// again, for up to `RECONNECT_TIMEOUT`. Unlike `wait_for_reconnect`, it
+/// doesn't need to see it go away first: after a glitch it may already be
+/// back.
+fn find_again(args: &Args, serial: &str) -> Result<DeviceSummary> {
+    let started = Instant::now();
+    let pid = if args.force_pid_any { None } else { Some(args.pid) };
+    loop {
+        let found = DualSenseHid::list(args.vid, pid)?
+            .into_iter()
+            .find(|device| device.serial.eq_ignore_ascii_case(serial));
+        if let Some(device) = found {
+            return Ok(device);
+        }
+        if started.elapsed() >= RECONNECT_TIMEOUT {
+            return Err(AppError::ReconnectTimeout {
+                secs: RECONNECT_TIMEOUT.as_secs(),
+            });
+        }
+        log::info!("Controller {} not back yet", serial);
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// `--wait-for-device`: polls every 500ms until a matching controller is
+/// attached, failing with the usual not-found error once `timeout` passes.
+fn wait_for_device(args: &Args, timeout: Duration) -> Result<()> {
+    let started = Instant::now();
+    let mut announced = false;
+    loop {
+        let found = if args.auto_detect {
+            detect_known_device().is_ok()
+        } else {
+            let pid = if args.force_pid_any { None } else { Some(args.pid) };
+            !DualSenseHid::list(args.vid, pid)?.is_empty()
+        };
+        if found {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(if args.auto_detect {
+                AppError::NoKnownDeviceFound
+            } else {
+                AppError::DeviceNotFound {
+                    vid: args.vid,
+                    pid: args.pid,
+                }
+            });
+        }
+        if !announced {
+            output::info("Waiting for DualSense...");
+            announced = true;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Picks the device to open: `--path` wins, then `--serial`, then the only
+/// match. With several matches the user chooses from a menu, unless `--yes`
+/// was given, in which case the ambiguity is an error.
+fn select_device_path(args: &Args) -> Result<String> {
+    if !args.path.is_empty() {
+        return Ok(args.path.clone());
+    }
+    if args.force_pid_any {
+        return select_any_pid_device_path(args);
+    }
+    let mut devices = DualSenseHid::list(args.vid, Some(args.pid))?;
+    if !args.serial.is_empty() {
+        devices.retain(|d| d.serial.eq_ignore_ascii_case(&args.serial));
+        if devices.is_empty() {
+            return Err(AppError::DeviceSerialNotMatched(args.serial.clone()));
+        }
+    }
+    match devices.len() {
+        0 => Err(AppError::DeviceNotFound {
+            vid: args.vid,
+            pid: args.pid,
+        }),
+        1 => Ok(devices.remove(0).path),
+        count if args.yes || args.headless => Err(AppError::AmbiguousDevice(count)),
+        _ => {
+            output::info("Multiple controllers found:");
+            for (idx, device) in devices.iter().enumerate() {
+                output::info(format!(
+                    "  [{}] serial={} product={} path={}",
+                    idx + 1,
+                    device.serial,
+                    device.product,
+                    device.path
+                ));
+            }
+            let choice = prompt_choice("Select a controller", devices.len())?;
+            Ok(devices.remove(choice).path)
+        }
+    }
+}
+
+/// `--force-pid-any`: offers every device from the vendor, whatever its PID,
+/// and always makes the user pick one explicitly.
+fn select_any_pid_device_path(args: &Args) -> Result<String> {
+    let mut devices = DualSenseHid::list(args.vid, None)?;
+    if !args.serial.is_empty() {
+        devices.retain(|d| d.serial.eq_ignore_ascii_case(&args.serial));
+    }
+    if devices.is_empty() {
+        return Err(AppError::DeviceNotFound {
+            vid: args.vid,
+            pid: args.pid,
+        });
+    }
+    output::warn(format!(
+        "Devices from vendor 0x{:04x} (any PID). Only continue if you are sure this is a DualSense:",
+        args.vid
+    ));
+    for (idx, device) in devices.iter().enumerate() {
+        output::info(format!(
+            "  [{}] pid=0x{:04x} serial={} product={} path={}",
+            idx + 1,
+            device.product_id,
+            device.serial,
+            device.product,
+            device.path
+        ));
+    }
+    if args.headless {
+        return Err(AppError::HeadlessPrompt {
+            what: "choosing a device with --force-pid-any",
+            answer_with: "--path",
+        });
+    }
+    let choice = prompt_choice("Select a device", devices.len())?;
+    Ok(devices.remove(choice).path)
+}
+
+fn check_battery(dev: &DualSenseHid, args: &Args) -> Result<()> {
+    let battery = match dev.read_battery() {
+        Ok(Some(battery)) => battery,
+        Ok(None) => {
+            output::warn("Battery level unknown, proceeding");
+            return Ok(());
+        }
+        Err(err) => {
+            log::debug!("Battery read failed: {err}");
+            output::warn("Battery level unknown, proceeding");
+            return Ok(());
+        }
+    };
+    if !args.quiet {
+        output::info(format!("Battery: {}% ({:?})", battery.level, battery.charging));
+    }
+    if battery.level >= args.min_battery {
+        return Ok(());
+    }
+    // USB power keeps a charging controller up through the flash.
+    let charging = matches!(battery.charging, ChargingState::Charging | ChargingState::Full);
+    if charging {
+        output::info(format!(
+            "Battery {}% is below --min-battery {}%, continuing because it's {:?}",
+            battery.level, args.min_battery, battery.charging
+        ));
+    } else if args.force {
+        output::warn("Battery is low, continuing because of --force");
+    } else {
+        return Err(AppError::BatteryTooLow {
+            level: battery.level,
+            required: args.min_battery,
+        });
+    }
+    Ok(())
+}
+
+/// `verbose` is the number of `-v` flags: none shows warnings only, then each
+/// one adds a level (info, debug, trace).
+fn init_logging(
+    verbose: u8,
+    log_file: Option<&std::path::Path>,
+    format: LogFormat,
+) -> Result<()> {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter_level(level);
+    builder.format_timestamp(None);
+    if format == LogFormat::Kv {
+        builder.format(|buf, record| format_kv(buf, record, false));
+    }
+    let Some(log_file) = log_file else {
+        builder.init();
+        return Ok(());
+    };
+    let file = std::fs::File::create(log_file)?;
+    let mut file_builder = env_logger::Builder::new();
+    file_builder
+        .filter_level(level.max(LevelFilter::Debug))
+        .format_timestamp_millis()
+        .target(env_logger::Target::Pipe(Box::new(file)));
+    if format == LogFormat::Kv {
+        file_builder.format(|buf, record| format_kv(buf, record, true));
+    }
+    let file_logger = file_builder.build();
+    let console = builder.build();
+    log::set_max_level(console.filter().max(file_logger.filter()));
+    // Only fails if a logger is already installed, which can't happen here.
+    let _ = log::set_boxed_logger(Box::new(TeeLogger {
+        console,
+        file: file_logger,
+    }));
+    Ok(())
+}
+
+/// `--log-format kv`: one line of `key=value` pairs per record, with the
+/// record's structured fields before the free-form message.
+fn format_kv(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+    timestamp: bool,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    struct Fields(String);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> std::result::Result<(), log::kv::Error> {
+            self.0.push_str(&format!(" {key}={value}"));
+            Ok(())
+        }
+    }
+
+    if timestamp {
+        write!(buf, "ts={} ", buf.timestamp_millis())?;
+    }
+    let mut fields = Fields(String::new());
+    let _ = record.key_values().visit(&mut fields);
+    writeln!(
+        buf,
+        "level={}{} msg={:?}",
+        record.level().as_str().to_lowercase(),
+        fields.0,
+        record.args().to_string()
+    )
+}
+
+/// Sends every record to the console logger and, at debug level, to the
+/// `--log-file` logger.
+struct TeeLogger {
+    console: env_logger::Logger,
+    file: env_logger::Logger,
+}
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console.enabled(metadata) || self.file.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.console.log(record);
+        self.file.log(record);
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        self.file.flush();
+    }
+}
+
+/// The first Ctrl-C during a flash asks the updater to stop at a chunk
+/// boundary; a second one, or any outside a flash, exits immediately.
+fn install_ctrlc_handler() {
+    let result = ctrlc::set_handler(|| {
+        if FLASHING.load(Ordering::SeqCst) && !CANCEL.swap(true, Ordering::SeqCst) {
+            output::warn("WARNING: interrupting mid-flash may require a re-run to recover. Stopping at the next chunk boundary; press Ctrl-C again to exit now.");
+        } else {
+            std::process::exit(130);
+        }
+    });
+    if let Err(err) = result {
+        log::debug!("Ctrl-C handler not installed: {err}");
+    }
+}
+
+/// Marks a flash as in progress for the Ctrl-C handler until dropped.
+struct FlashGuard;
+
+impl FlashGuard {
+    fn start() -> Self {
+        CANCEL.store(false, Ordering::SeqCst);
+        FLASHING.store(true, Ordering::SeqCst);
+        FlashGuard
+    }
+}
+
+impl Drop for FlashGuard {
+    fn drop(&mut self) {
+        FLASHING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Parses the command line. `--vid`/`--pid` also come from `DS_UPDATER_VID`/
+/// `DS_UPDATER_PID`, so their conflicts are checked here, against flags given
+/// on the command line only: a PID set in the environment shouldn't break
+/// `--auto-detect`.
+fn parse_args() -> std::result::Result<Args, clap::Error> {
+    use clap::error::ErrorKind;
+    use clap::parser::ValueSource;
+    use clap::FromArgMatches;
+
+    let matches = Args::command().try_get_matches()?;
+    let mut args = Args::from_arg_matches(&matches)?;
+    let config = Config::load(args.config.as_deref())
+        .map_err(|msg| Args::command().error(ErrorKind::Io, msg))?;
+    if let Some((path, config)) = config {
+        config.apply(&mut args, |id| {
+            matches.value_source(id) == Some(ValueSource::DefaultValue)
+        });
+        args.config = Some(path);
+    }
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if args.auto_detect && (given("vid") || given("pid")) {
+        return Err(Args::command().error(
+            ErrorKind::ArgumentConflict,
+            "--auto-detect can't be used with --vid or --pid",
+        ));
+    }
+    if args.force_pid_any && given("pid") {
+        return Err(Args::command().error(
+            ErrorKind::ArgumentConflict,
+            "--force-pid-any can't be used with --pid",
+        ));
+    }
+    Ok(args)
 }
 
 fn print_help() {
@@ -141,27 +1565,85 @@ fn print_help() {
     println!();
 }
 
+/// Process exit codes, stable so scripts can branch on the outcome:
+///
+/// - 1: other errors (usage, unexpected I/O)
+/// - 2: device not found
+/// - 3: permission denied opening or talking to the device
+/// - 4: firmware image missing or invalid
+/// - 5: update rejected by the device
+/// - 6: HID I/O or protocol error
+fn exit_code(err: &AppError) -> i32 {
+    match err {
+        AppError::DeviceNotFound { .. }
+        | AppError::NoKnownDeviceFound
+        | AppError::DevicePathNotMatched(_)
+        | AppError::DeviceSerialNotMatched(_)
+        | AppError::SerialMismatch { .. } => 2,
+        AppError::Hid(hid_err) if is_permission_error(hid_err) => 3,
+        AppError::Io(io_err) if io_err.kind() == std::io::ErrorKind::PermissionDenied => 3,
+        AppError::VersionOffsetOutOfRange { .. }
+        | AppError::FirmwareImageTooSmallForHeader
+        | AppError::FirmwareImageImplausibleSize { .. }
+        | AppError::FirmwareImageMalformed(_)
+        | AppError::ImageOffsetOutOfRange { .. }
+        | AppError::ImageDecompressFailed(_)
+        | AppError::ImageSignatureInvalid
+        | AppError::SignatureCheckFailed(_)
+        | AppError::ImageVersionImplausible(_)
+        | AppError::InvalidUpdateStreamLength(_)
+        | AppError::UpdateImageTooLarge(_) => 4,
+        AppError::UpdateFailed(_) | AppError::PostUpdateVersionMismatch { .. } => 5,
+        AppError::ReplayLogMalformed { .. } | AppError::ReplayDiverged { .. } => 1,
+        AppError::Hid(_)
+        | AppError::ShortFeatureReport { .. }
+        | AppError::FirmwareInfoTooShort(_)
+        | AppError::FirmwareInfoPayloadTooShort(_)
+        | AppError::FirmwareInfoUnrecognized(_)
+        | AppError::DeviceInRecoveryMode
+        | AppError::UpdateStatusEmpty
+        | AppError::UpdateStatusMalformed(_)
+        | AppError::UnknownStatusCommandByte(_)
+        | AppError::UnexpectedUpdateStatusCommand(_, _)
+        | AppError::StatusCommandDrift { .. }
+        | AppError::UpdateTimeout { .. }
+        | AppError::TooManyRetries { .. } => 6,
+        AppError::MissingFirmwareImageForUpdate
+        | AppError::MissingFirmwareImageForInteractive
+        | AppError::AmbiguousDevice(_)
+        | AppError::BatteryTooLow { .. }
+        | AppError::FinalizeWithoutVerify
+        | AppError::Cancelled { .. }
+        | AppError::ReconnectTimeout { .. }
+        | AppError::DeviceBusy { .. }
+        | AppError::ResetNotSupported
+        | AppError::AbortNotSupported
+        | AppError::HeadlessPrompt { .. }
+        | AppError::Io(_) => 1,
+    }
+}
+
+fn is_permission_error(err: &hidapi::HidError) -> bool {
+    HidErrno::classify(err) == Some(HidErrno::Eacces)
+}
+
+/// Appends a code only where one exists: the failing step and status for
+/// device rejections, the OS errno for I/O errors.
 fn format_error(err: &AppError) -> String {
     match err {
         AppError::UpdateFailed(failure) => {
             let message = update_failure_message(failure);
             format!("{message} ({})", update_failure_debug(failure))
         }
-        AppError::DeviceNotFound { .. } => format!("{err} (0x00)"),
-        AppError::DevicePathNotMatched(_) => format!("{err} (0x00)"),
-        AppError::MissingFirmwareImageForUpdate => format!("{err} (0x00)"),
-        AppError::MissingFirmwareImageForInteractive => format!("{err} (0x00)"),
-        AppError::FirmwareImageTooSmall => format!("{err} (0x00)"),
-        AppError::FirmwareImageTooSmallForHeader => format!("{err} (0x00)"),
-        AppError::InvalidUpdateStreamLength(_) => format!("{err} (0x00)"),
-        AppError::UpdateImageTooLarge(_) => format!("{err} (0x00)"),
-        AppError::FirmwareInfoTooShort(_) => format!("{err} (0x00)"),
-        AppError::FirmwareInfoPayloadTooShort(_) => format!("{err} (0x00)"),
-        AppError::UpdateStatusEmpty => format!("{err} (0x00)"),
-        AppError::UpdateStatusMalformed(_) => format!("{err} (0x00)"),
-        AppError::UnexpectedUpdateStatusCommand(_, _) => format!("{err} (0x00)"),
-        AppError::Hid(_) => format!("{err} (0x00)"),
-        AppError::Io(_) => format!("{err} (0x00)"),
+        AppError::Hid(hid_err) => match HidErrno::classify(hid_err) {
+            Some(errno) => format!("{err} ({}: {})", errno.name(), errno.remedy()),
+            None => err.to_string(),
+        },
+        AppError::Io(io_err) => match io_err.raw_os_error() {
+            Some(errno) => format!("{err} (errno {errno})"),
+            None => err.to_string(),
+        },
+        _ => err.to_string(),
     }
 }
 
@@ -198,7 +1680,7 @@ fn start_update_message(err: StartUpdateError) -> String {
             "Firmware image header authentication failed.".to_string()
         }
         StartUpdateError::HeaderCapabilityInfoError => {
-            "Firmware image header capability info is invalid.".to_string()
+            "Firmware image header capability info was rejected; the image is probably for a different controller model (e.g. a DualSense Edge image on a standard DualSense). Nothing was written.".to_string()
         }
         StartUpdateError::HeaderFlashEraseError => {
             "Device failed to erase flash for the update.".to_string()
@@ -263,13 +1745,71 @@ fn finalize_update_message(err: FinalizeUpdateError) -> String {
     }
 }
 
-fn prompt_yes_no(prompt: &str) -> Result<bool> {
-    use std::io::{self, Write};
+/// Reads a line from stdin, or returns `None` if `timeout` passes first. The
+/// read happens on a helper thread, which is simply left blocked on timeout.
+fn read_line_timeout(timeout: Option<Duration>) -> Result<Option<String>> {
+    let read_line = || {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).map(|_| input)
+    };
+    let Some(timeout) = timeout else {
+        return Ok(Some(read_line()?));
+    };
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(read_line());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(input) => Ok(Some(input?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Waits for the user to press Enter. Returns `false` once stdin is closed.
+fn wait_for_enter(prompt: &str) -> Result<bool> {
+    use std::io;
+    output::prompt(prompt)?;
+    let mut input = String::new();
+    Ok(io::stdin().read_line(&mut input)? > 0)
+}
+
+/// Asks for a number in `1..=count` and returns it as a zero-based index.
+fn prompt_choice(prompt: &str, count: usize) -> Result<usize> {
+    use std::io;
     loop {
-        print!("{} [y/N] ", prompt);
-        io::stdout().flush()?;
+        output::prompt(format!("{} [1-{}]:", prompt, count))?;
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Err(AppError::AmbiguousDevice(count));
+        }
+        match input.trim().parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => return Ok(n - 1),
+            _ => output::warn(format!("Please enter a number between 1 and {}.", count)),
+        }
+    }
+}
+
+/// A yes/no question that `--yes` answers up front. With `--headless` it
+/// fails, naming `what` was being asked, instead of reading stdin.
+fn confirm_prompt(args: &Args, what: &'static str, prompt: &str) -> Result<bool> {
+    if args.headless {
+        return Err(AppError::HeadlessPrompt {
+            what,
+            answer_with: "--yes",
+        });
+    }
+    prompt_yes_no(prompt, args.prompt_timeout.map(Duration::from_secs))
+}
+
+/// With a `timeout`, no answer in time counts as "no".
+fn prompt_yes_no(prompt: &str, timeout: Option<Duration>) -> Result<bool> {
+    loop {
+        output::prompt(format!("{} [y/N]", prompt))?;
+        let Some(input) = read_line_timeout(timeout)? else {
+            output::info("");
+            output::warn("No answer in time; assuming no.");
+            return Ok(false);
+        };
         let reply = input.trim().to_lowercase();
         if reply.is_empty() || reply == "n" || reply == "no" {
             return Ok(false);
@@ -277,6 +1817,41 @@ fn prompt_yes_no(prompt: &str) -> Result<bool> {
         if reply == "y" || reply == "yes" {
             return Ok(true);
         }
-        println!("Please enter 'y' or 'n'.");
+        output::warn("Please enter 'y' or 'n'.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use dualsense_updater::mock_hid::MockHid;
+
+    use super::*;
+    use crate::protocol::IMAGE_VERSION_OFFSET;
+
+    fn args(extra: &[&str]) -> Args {
+        let base = ["dualsense-updater", "--vid", "0x054c", "--pid", "0x0ce6"];
+        Args::parse_from(base.iter().chain(extra))
+    }
+
+    /// A hand-written transfer log in the `--record` format, not a capture.
+    const SYNTHETIC_SESSION: &str = include_str!("../testdata/synthetic_session.log");
+
+    /// The 1 KiB image `SYNTHETIC_SESSION` flashes, for version 0x0520.
+    fn session_image() -> Vec<u8> {
+        let mut image: Vec<u8> = (0..1024u32).map(|i| (i * 7 + 3) as u8).collect();
+        image[IMAGE_VERSION_OFFSET..IMAGE_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x0520u16.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn synthetic_session_replays_through_flash_with() {
+        let dev = MockHid::from_recording(SYNTHETIC_SESSION);
+        let sent = dev.sent_log();
+        let args = args(&["--yes", "--quiet", "--poll-interval-ms", "1"]);
+        let outcome = flash_with(&args, dev, None, &session_image(), false).unwrap();
+        assert!(matches!(outcome, FlashOutcome::Flashed));
+        assert_eq!(*sent.borrow(), MockHid::recorded_sends(SYNTHETIC_SESSION));
     }
 }
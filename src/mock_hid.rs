@@ -0,0 +1,118 @@
+//! A scripted `HidTransport` for tests: status reports are answered from a
+//! queue set up by the test, and every command sent is kept for asserting on.
+//! The queue can also be loaded from a `--record` transfer log. Built for
+//! the library's own tests and, with the `test-util` feature, for the
+//! binary's.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::error::{AppError, Result};
+use crate::hid::HidTransport;
+use crate::protocol::{
+    FirmwareInfo, UpdateCommand, UpdateStatus, FIRMWARE_INFO_MIN_LEN, REPORT_ID_FIRMWARE_INFO,
+    REPORT_ID_UPDATE_STATUS,
+};
+
+#[derive(Default)]
+pub struct MockHid {
+    statuses: RefCell<VecDeque<Vec<u8>>>,
+    firmware_info: Option<Vec<u8>>,
+    sent: SentLog,
+}
+
+/// The commands a `MockHid` was sent, shared so they can still be read after
+/// the mock has been moved into an updater.
+pub type SentLog = Rc<RefCell<Vec<(UpdateCommand, Vec<u8>)>>>;
+
+impl MockHid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues one `[0xF5, command, status, 0]` report per status byte.
+    pub fn statuses(self, command: UpdateCommand, codes: &[u8]) -> Self {
+        self.statuses.borrow_mut().extend(
+            codes
+                .iter()
+                .map(|code| vec![REPORT_ID_UPDATE_STATUS, command as u8, *code, 0]),
+        );
+        self
+    }
+
+    /// Answers with the `status` and `info` reports of a transfer log, in
+    /// order. The `send` lines are left for the test to compare against
+    /// `sent` (see `recorded_sends`).
+    pub fn from_recording(log: &str) -> Self {
+        let mut mock = Self::new();
+        for (kind, bytes) in recorded(log) {
+            match kind {
+                "status" => mock.statuses.get_mut().push_back(bytes),
+                "info" => mock.firmware_info = Some(bytes),
+                _ => {}
+            }
+        }
+        mock
+    }
+
+    /// The commands a transfer log shows being sent, in order.
+    pub fn recorded_sends(log: &str) -> Vec<(UpdateCommand, Vec<u8>)> {
+        recorded(log)
+            .filter(|(kind, _)| *kind == "send")
+            .map(|(_, bytes)| (UpdateCommand::from_int(bytes[0]), bytes[1..].to_vec()))
+            .collect()
+    }
+
+    /// Every command sent so far, in order.
+    pub fn sent(&self) -> Vec<(UpdateCommand, Vec<u8>)> {
+        self.sent.borrow().clone()
+    }
+
+    pub fn sent_log(&self) -> SentLog {
+        Rc::clone(&self.sent)
+    }
+
+    /// Status reports still queued.
+    pub fn remaining(&self) -> usize {
+        self.statuses.borrow().len()
+    }
+}
+
+impl HidTransport for MockHid {
+    fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
+        self.sent.borrow_mut().push((command, payload.to_vec()));
+        Ok(())
+    }
+
+    fn get_update_status(&self, _length: usize) -> Result<UpdateStatus> {
+        let raw = self.statuses.borrow_mut().pop_front();
+        UpdateStatus::parse(&raw.expect("MockHid: status script ran out"))
+    }
+
+    fn get_firmware_info(&self) -> Result<FirmwareInfo> {
+        match &self.firmware_info {
+            Some(raw) => FirmwareInfo::parse(raw.clone()),
+            None => Err(AppError::ShortFeatureReport {
+                report_id: REPORT_ID_FIRMWARE_INFO,
+                got: 0,
+                expected: FIRMWARE_INFO_MIN_LEN,
+            }),
+        }
+    }
+}
+
+/// The `<kind> <hex>` lines of a transfer log, skipping comments.
+fn recorded(log: &str) -> impl Iterator<Item = (&str, Vec<u8>)> {
+    log.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (kind, hex) = line.split_once(' ').unwrap_or((line, ""));
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("MockHid: bad hex in log"))
+                .collect();
+            (kind, bytes)
+        })
+}
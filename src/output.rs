@@ -0,0 +1,137 @@
+use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// With `--format json`, stdout carries only JSON documents (see `json`), so
+/// every human-readable message goes to stderr instead.
+static TEXT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Lines of the live status block currently on screen, so the next message
+/// can erase it first. Always 0 unless the `tui` feature draws one.
+static STATUS_LINES: AtomicUsize = AtomicUsize::new(0);
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Enables color only when the text stream is a terminal, `NO_COLOR` is
+/// unset and `--no-color` wasn't given. `json` moves text to stderr.
+pub fn init(no_color: bool, json: bool) {
+    TEXT_TO_STDERR.store(json, Ordering::Relaxed);
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let terminal = if json {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    COLOR.store(!no_color && !no_color_env && terminal, Ordering::Relaxed);
+}
+
+/// Prints one JSON document on stdout, whatever the format.
+pub fn json(value: impl Display) {
+    println!("{value}");
+}
+
+pub fn info(msg: impl Display) {
+    clear_status();
+    print_text(msg);
+}
+
+/// Prints a question without a newline, so the answer is typed after it.
+pub fn prompt(msg: impl Display) -> std::io::Result<()> {
+    clear_status();
+    if TEXT_TO_STDERR.load(Ordering::Relaxed) {
+        eprint!("{msg} ");
+        std::io::stderr().flush()
+    } else {
+        print!("{msg} ");
+        std::io::stdout().flush()
+    }
+}
+
+fn print_text(msg: impl Display) {
+    if TEXT_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{msg}");
+    } else {
+        println!("{msg}");
+    }
+}
+
+pub fn success(msg: impl Display) {
+    print_colored(GREEN, msg);
+}
+
+pub fn warn(msg: impl Display) {
+    print_colored(YELLOW, msg);
+}
+
+pub fn error(msg: impl Display) {
+    print_colored(RED, msg);
+}
+
+fn print_colored(color: &str, msg: impl Display) {
+    clear_status();
+    if COLOR.load(Ordering::Relaxed) {
+        print_text(format_args!("{color}{msg}{RESET}"));
+    } else {
+        print_text(msg);
+    }
+}
+
+/// Replaces the live status block with `lines`. Messages printed in between
+/// erase it, so they scroll by above the next redraw.
+#[cfg(feature = "tui")]
+pub fn draw_status(lines: &[String]) {
+    clear_status();
+    for line in lines {
+        println!("{line}");
+    }
+    STATUS_LINES.store(lines.len(), Ordering::Relaxed);
+}
+
+fn clear_status() {
+    let lines = STATUS_LINES.swap(0, Ordering::Relaxed);
+    if lines > 0 {
+        // Cursor up to the first status line, then clear to end of screen.
+        print!("\x1b[{lines}A\x1b[J");
+    }
+}
+
+/// Formats `data` as a hexdump, 16 bytes per row with offsets and an ASCII
+/// gutter.
+pub fn hexdump(data: &[u8]) -> String {
+    hexdump_marked(data, |_| false)
+}
+
+/// Like `hexdump`, but bytes whose offset satisfies `marked` are highlighted
+/// when color is enabled.
+pub fn hexdump_marked(data: &[u8], marked: impl Fn(usize) -> bool) -> String {
+    let color = COLOR.load(Ordering::Relaxed);
+    data.chunks(16)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let hex = bytes
+                .iter()
+                .enumerate()
+                .map(|(col, b)| {
+                    if color && marked(row * 16 + col) {
+                        format!("{YELLOW}{:02x}{RESET}", b)
+                    } else {
+                        format!("{:02x}", b)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let padding = " ".repeat((16 - bytes.len()) * 3);
+            let ascii = bytes
+                .iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect::<String>();
+            format!("{:08x}  {}{}  |{}|", row * 16, hex, padding, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,121 @@
+//! Where the update phases report per-chunk progress. The console sink prints
+//! a line per chunk; `--quiet` swaps in a sink that drops them. With the `tui`
+//! feature, `crate::tui` adds a sink that redraws a live status block, and
+//! `--event-log` wraps whichever sink is in use to record every event.
+//! `--format json` uses a sink that prints a progress event per chunk.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::output;
+use crate::protocol::{UpdateCommand, UpdateStatus, WriteUpdateStatusCode, WRITE_CHUNK_LEN};
+
+pub trait ProgressSink {
+    /// Called as StartUpdate, WriteUpdateImage, VerifyUpdateImage and
+    /// FinalizeUpdate begin.
+    fn phase_started(&self, _command: UpdateCommand) {}
+
+    /// Called before each 0xF4 command report is sent.
+    fn command_sent(&self, _command: UpdateCommand, _payload: &[u8]) {}
+
+    /// Called for each 0xF5 status report read while waiting on a command.
+    fn status_received(&self, _status: &UpdateStatus) {}
+
+    /// Called every few seconds while VerifyUpdateImage is still answering
+    /// KeepPolling, so a long verify doesn't look like a hang.
+    fn verify_waiting(&self, _elapsed: Duration, _timeout: Duration) {}
+
+    /// Called once a 0x8000-byte chunk has been fully acknowledged.
+    fn chunk_written(&self, idx: usize, status: WriteUpdateStatusCode);
+}
+
+pub struct ConsoleProgress;
+
+impl ProgressSink for ConsoleProgress {
+    fn verify_waiting(&self, elapsed: Duration, timeout: Duration) {
+        output::info(format!(
+            "Still verifying after {}s (giving up at {}s)",
+            elapsed.as_secs(),
+            timeout.as_secs()
+        ));
+    }
+
+    fn chunk_written(&self, idx: usize, status: WriteUpdateStatusCode) {
+        output::info(format!(
+            "WriteUpdateImage chunk {}: {} (0x{:02x})",
+            idx,
+            status.name(),
+            status as u8
+        ));
+    }
+}
+
+pub struct QuietProgress;
+
+impl ProgressSink for QuietProgress {
+    fn chunk_written(&self, _idx: usize, _status: WriteUpdateStatusCode) {}
+}
+
+/// Prints one JSON line per written chunk for front-ends drawing their own
+/// progress bar:
+///
+/// ```text
+/// {"event":"progress","phase":"write","percent":42.8,"bytes":589824,"total":1376256,"eta_secs":9.1}
+/// ```
+///
+/// `percent`, `total` and `eta_secs` are null when the image size isn't known
+/// up front; `eta_secs` is also null until the first chunk is acknowledged.
+pub struct JsonProgress {
+    total_bytes: usize,
+    /// When WriteUpdateImage started and how many bytes were already on the
+    /// device then (non-zero when resuming), for the ETA.
+    write_started: Mutex<Option<(Instant, Option<usize>)>>,
+}
+
+impl JsonProgress {
+    /// `total_bytes` is the image size, or 0 if it isn't known.
+    pub fn new(total_bytes: usize) -> Self {
+        Self {
+            total_bytes,
+            write_started: Mutex::new(None),
+        }
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn phase_started(&self, command: UpdateCommand) {
+        if command == UpdateCommand::WriteUpdateImage {
+            *self.write_started.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((Instant::now(), None));
+        }
+    }
+
+    fn chunk_written(&self, idx: usize, _status: WriteUpdateStatusCode) {
+        let mut bytes = (idx + 1) * WRITE_CHUNK_LEN;
+        if self.total_bytes > 0 {
+            bytes = bytes.min(self.total_bytes);
+        }
+        let mut write_started = self.write_started.lock().unwrap_or_else(|e| e.into_inner());
+        let (started, first_bytes) = write_started.get_or_insert((Instant::now(), None));
+        let first_bytes = *first_bytes.get_or_insert(idx * WRITE_CHUNK_LEN);
+        let (percent, total, eta_secs) = if self.total_bytes > 0 {
+            let percent = (bytes as f64 * 1000.0 / self.total_bytes as f64).round() / 10.0;
+            let rate = (bytes - first_bytes) as f64 / started.elapsed().as_secs_f64();
+            let eta = (rate > 0.0)
+                .then(|| ((self.total_bytes - bytes) as f64 / rate * 10.0).round() / 10.0);
+            (Some(percent), Some(self.total_bytes), eta)
+        } else {
+            (None, None, None)
+        };
+        output::json(json!({
+            "event": "progress",
+            "phase": "write",
+            "percent": percent,
+            "bytes": bytes,
+            "total": total,
+            "eta_secs": eta_secs,
+        }));
+    }
+}
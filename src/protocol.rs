@@ -1,27 +1,397 @@
+pub const REPORT_ID_INPUT_USB: u8 = 0x01;
 pub const REPORT_ID_FIRMWARE_INFO: u8 = 0x20;
 pub const REPORT_ID_UPDATE_COMMAND: u8 = 0xF4;
 pub const REPORT_ID_UPDATE_STATUS: u8 = 0xF5;
 
+/// Known Sony DualSense VID/PID pairs, checked in order by `--auto-detect`.
+pub const KNOWN_DEVICES: &[(u16, u16, &str)] = &[
+    (0x054c, 0x0ce6, "DualSense"),
+    (0x054c, 0x0df2, "DualSense Edge"),
+];
+
+/// Which controller a VID/PID belongs to. The raw IDs stay the source of
+/// truth for opening devices; this is for display and model-specific checks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Model {
+    StandardDualSense,
+    DualSenseEdge,
+    Unknown,
+}
+
+impl Model {
+    pub fn from_ids(vid: u16, pid: u16) -> Self {
+        match (vid, pid) {
+            (0x054c, 0x0ce6) => Self::StandardDualSense,
+            (0x054c, 0x0df2) => Self::DualSenseEdge,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::StandardDualSense => "DualSense",
+            Self::DualSenseEdge => "DualSense Edge",
+            Self::Unknown => "Unknown controller",
+        }
+    }
+}
+
+/// Largest payload carried by one 0xF4 update command report (the report ID,
+/// command and length bytes come before it).
+pub const MAX_UPDATE_PAYLOAD: usize = 0x39;
+
+/// Length of a firmware info report (including the report ID) needed to
+/// reach the version field.
+pub const FIRMWARE_INFO_MIN_LEN: usize = 46;
+
+/// WriteUpdateImage sends the image in chunks of this size, each acknowledged
+/// before the next.
+pub const WRITE_CHUNK_LEN: usize = 0x8000;
+
+pub const IMAGE_HEADER_LEN: usize = 256;
+pub const IMAGE_VERSION_OFFSET: usize = 0x78;
+/// Bounds on a plausible firmware image size. Real images are around 1MB;
+/// anything far outside that is a partial download or the wrong file.
+pub const MIN_IMAGE_LEN: usize = 100 * 1024;
+pub const MAX_IMAGE_LEN: usize = 4 * 1024 * 1024;
+
+use std::cmp::Ordering;
+use std::ops::{Range, RangeInclusive};
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::error::{AppError, Result};
+
+/// The 256-byte header at the start of a firmware image, sent as-is with
+/// StartUpdate. Only the version field is understood; the rest is opaque.
+/// That includes the capability info the device checks against its model, so
+/// a wrong-model image can't be caught here. The device rejects it at
+/// StartUpdate with HEADER_CAPABILITY_INFO_ERROR, before anything is written.
+/// There is no known magic number, so a header that doesn't start at byte 0
+/// (e.g. inside a wrapper container) can't be located automatically.
+#[derive(Debug, Clone)]
+pub struct ImageHeader {
+    pub firmware_version: u16,
+    pub body_len: usize,
+}
+
+impl ImageHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_with_version_field(data, VersionField::default())
+    }
+
+    /// Like `parse`, but reads the version from `version` instead of the
+    /// little-endian word at `IMAGE_VERSION_OFFSET`, for image formats that
+    /// have moved or changed it.
+    pub fn parse_with_version_field(data: &[u8], version: VersionField) -> Result<Self> {
+        if data.len() < IMAGE_HEADER_LEN {
+            return Err(AppError::FirmwareImageTooSmallForHeader);
+        }
+        let header = &data[..IMAGE_HEADER_LEN];
+        if header.iter().all(|b| *b == 0x00) || header.iter().all(|b| *b == 0xFF) {
+            return Err(AppError::FirmwareImageMalformed("header is blank"));
+        }
+        let firmware_version = version.read(data)?;
+        // No second copy of the version is known in the header, so the best
+        // we can do is reject values an erased or zero-filled file would give.
+        if firmware_version == 0x0000 || firmware_version == 0xFFFF {
+            return Err(AppError::ImageVersionImplausible(firmware_version));
+        }
+        Ok(Self {
+            firmware_version,
+            body_len: data.len() - IMAGE_HEADER_LEN,
+        })
+    }
+
+    /// Structural checks on the body. The body is authenticated on-device
+    /// with a keyed CMAC we can't reproduce, so this only catches files that
+    /// are obviously truncated or blank. `data` shorter than the header is
+    /// treated as having no body rather than trusted to have been parsed.
+    pub fn validate_body(&self, data: &[u8]) -> Result<()> {
+        let body = data.get(IMAGE_HEADER_LEN..).unwrap_or_default();
+        if body.len() != self.body_len || body.is_empty() {
+            return Err(AppError::FirmwareImageMalformed("image has no body"));
+        }
+        if body.iter().all(|b| *b == body[0]) {
+            return Err(AppError::FirmwareImageMalformed("body is blank"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// Where a firmware image keeps its version word. Every image seen so far
+/// has it little-endian at `IMAGE_VERSION_OFFSET`.
+#[derive(Debug, Clone, Copy)]
+pub struct VersionField {
+    /// May lie past the header.
+    pub offset: usize,
+    pub endian: Endian,
+}
+
+impl Default for VersionField {
+    fn default() -> Self {
+        Self {
+            offset: IMAGE_VERSION_OFFSET,
+            endian: Endian::Little,
+        }
+    }
+}
+
+impl VersionField {
+    pub fn read(&self, image: &[u8]) -> Result<u16> {
+        let Some(&[lo, hi]) = image.get(self.offset..self.offset.saturating_add(2)) else {
+            return Err(AppError::VersionOffsetOutOfRange {
+                offset: self.offset,
+                file_len: image.len(),
+            });
+        };
+        Ok(match self.endian {
+            Endian::Little => u16::from_le_bytes([lo, hi]),
+            Endian::Big => u16::from_be_bytes([lo, hi]),
+        })
+    }
+}
+
+/// Where the decoded fields sit in a firmware info report of a given length.
+/// Offsets are `(start, end)` relative to a report that starts with the
+/// report ID.
+struct FirmwareInfoLayout {
+    lens: RangeInclusive<usize>,
+    build_date: (usize, usize),
+    build_time: (usize, usize),
+    hw_id: (usize, usize),
+    firmware_version: (usize, usize),
+}
+
+/// The 64-byte report, the same layout the Linux hid-playstation driver
+/// reads.
+const FIRMWARE_INFO_LAYOUT_64: FirmwareInfoLayout = FirmwareInfoLayout {
+    lens: 64..=64,
+    build_date: (1, 12),
+    build_time: (12, 20),
+    hw_id: (24, 28),
+    firmware_version: (44, 46),
+};
+
+/// Known report layouts, picked by report length. Only the 64-byte report
+/// has been mapped. Other lengths, such as the 48-byte reports users have
+/// mentioned, fail with `FirmwareInfoUnrecognized` rather than being decoded
+/// from guessed offsets; a `--save-info` capture is what's needed to add one.
+const FIRMWARE_INFO_LAYOUTS: &[FirmwareInfoLayout] = &[FIRMWARE_INFO_LAYOUT_64];
+
+impl FirmwareInfoLayout {
+    /// `len` counts the report ID even if the backend stripped it.
+    fn for_len(len: usize) -> Option<&'static Self> {
+        FIRMWARE_INFO_LAYOUTS.iter().find(|layout| layout.lens.contains(&len))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FirmwareInfo {
     pub build_date: String,
     pub build_time: String,
     pub firmware_version: u16,
-    #[allow(dead_code)]
+    /// Hardware info word, little-endian at 24..28 (the Linux hid-playstation
+    /// driver reads it from the same place). How its values map to models
+    /// such as BDM-010 vs BDM-050 isn't known yet.
+    pub hw_id: u32,
     pub unknown: Vec<u8>,
-    #[allow(dead_code)]
     pub raw: Vec<u8>,
 }
 
+impl FirmwareInfo {
+    /// Parses a firmware info feature report. Field offsets are relative to a
+    /// report that starts with the report ID; if the backend stripped it, the
+    /// offsets are shifted accordingly.
+    pub fn parse(raw: Vec<u8>) -> Result<Self> {
+        if raw.len() < 20 {
+            return Err(AppError::FirmwareInfoTooShort(raw.len()));
+        }
+        let shift = if raw[0] == REPORT_ID_FIRMWARE_INFO { 0 } else { 1 };
+        // A controller whose last update was interrupted still answers, but
+        // with a blank report instead of the build info.
+        let payload = &raw[1 - shift..];
+        if payload.iter().all(|b| *b == 0x00) || payload.iter().all(|b| *b == 0xFF) {
+            return Err(AppError::DeviceInRecoveryMode);
+        }
+        // Every layout ends within FIRMWARE_INFO_MIN_LEN, so once the length
+        // check below passes `field` can't slice past the end of `raw`.
+        let field = |(start, end): (usize, usize)| &raw[start - shift..end - shift];
+        if raw.len() + shift < FIRMWARE_INFO_MIN_LEN {
+            return Err(AppError::FirmwareInfoPayloadTooShort(raw.len()));
+        }
+        let Some(layout) = FirmwareInfoLayout::for_len(raw.len() + shift) else {
+            return Err(AppError::FirmwareInfoUnrecognized(raw.len()));
+        };
+        let date = field(layout.build_date);
+        let time = field(layout.build_time);
+        if !is_ascii_field(date) || !is_ascii_field(time) {
+            return Err(AppError::FirmwareInfoUnrecognized(raw.len()));
+        }
+        let build_date = decode_ascii(date);
+        let build_time = decode_ascii(time);
+        let version = field(layout.firmware_version);
+        let firmware_version = u16::from_le_bytes([version[0], version[1]]);
+        let hw = field(layout.hw_id);
+        let hw_id = u32::from_le_bytes([hw[0], hw[1], hw[2], hw[3]]);
+        let unknown = raw[20 - shift..].to_vec();
+        Ok(Self {
+            build_date,
+            build_time,
+            firmware_version,
+            hw_id,
+            unknown,
+            raw,
+        })
+    }
+
+    pub fn version_string(&self) -> String {
+        format_version(self.firmware_version)
+    }
+
+    /// Orders two firmware versions, e.g. 0x0520 < 0x0600 < 0x0630. Reading
+    /// the high byte as major and the low byte as minor (see
+    /// `format_version`) orders them the same as the plain `u16`s, so no
+    /// version check depends on that split being right.
+    pub fn cmp_version(a: u16, b: u16) -> Ordering {
+        a.cmp(&b)
+    }
+
+    /// Byte ranges of the decoded fields within `raw`.
+    pub fn known_fields(&self) -> [(&'static str, Range<usize>); 4] {
+        let shift = if self.raw.first() == Some(&REPORT_ID_FIRMWARE_INFO) { 0 } else { 1 };
+        // `parse` only succeeds when a layout matched.
+        let layout = FirmwareInfoLayout::for_len(self.raw.len() + shift)
+            .unwrap_or(&FIRMWARE_INFO_LAYOUT_64);
+        let range = |(start, end): (usize, usize)| start - shift..end - shift;
+        [
+            ("build_date", range(layout.build_date)),
+            ("build_time", range(layout.build_time)),
+            ("hw_id", range(layout.hw_id)),
+            ("firmware_version", range(layout.firmware_version)),
+        ]
+    }
+}
+
+impl Serialize for FirmwareInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("FirmwareInfo", 5)?;
+        state.serialize_field("build_date", &self.build_date)?;
+        state.serialize_field("build_time", &self.build_time)?;
+        state.serialize_field("firmware_version", &self.firmware_version)?;
+        state.serialize_field("firmware_version_hex", &self.version_string())?;
+        state.serialize_field("hw_id", &format!("0x{:08x}", self.hw_id))?;
+        state.end()
+    }
+}
+
+/// How firmware versions are shown everywhere. Kept as plain hex: the
+/// major/minor meaning of the two bytes hasn't been confirmed.
+pub fn format_version(version: u16) -> String {
+    format!("0x{version:04x}")
+}
+
+fn is_ascii_field(data: &[u8]) -> bool {
+    data.iter()
+        .take_while(|b| **b != 0)
+        .all(|b| b.is_ascii_graphic() || *b == b' ')
+}
+
+/// Returns the declared size in bytes (excluding the report ID) of feature
+/// report `report_id` in a HID report descriptor. Only short items and the
+/// Report ID/Size/Count globals are interpreted.
+pub fn feature_report_len(descriptor: &[u8], report_id: u8) -> Option<usize> {
+    let mut idx = 0;
+    let mut report_size = 0u32;
+    let mut report_count = 0u32;
+    let mut current_id = 0u8;
+    let mut bits = 0u32;
+    let mut found = false;
+    while idx < descriptor.len() {
+        let prefix = descriptor[idx];
+        if prefix == 0xFE {
+            let size = *descriptor.get(idx + 1)? as usize;
+            idx += 3 + size;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let data = descriptor.get(idx + 1..idx + 1 + size)?;
+        let value = data.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        match prefix & 0xFC {
+            0x74 => report_size = value,
+            0x84 => current_id = value as u8,
+            0x94 => report_count = value,
+            // A malformed descriptor can declare sizes that overflow; treat
+            // it like any other unparseable one.
+            0xB0 if current_id == report_id => {
+                bits = bits.checked_add(report_size.checked_mul(report_count)?)?;
+                found = true;
+            }
+            _ => {}
+        }
+        idx += 1 + size;
+    }
+    found.then_some((bits / 8) as usize)
+}
+
 pub fn decode_ascii(data: &[u8]) -> String {
     let trimmed = data
         .iter()
         .copied()
         .take_while(|b| *b != 0)
         .collect::<Vec<u8>>();
-    String::from_utf8_lossy(&trimmed).to_string()
+    String::from_utf8_lossy(&trimmed).trim().to_string()
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChargingState {
+    Discharging,
+    Charging,
+    Full,
+    Error,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BatteryStatus {
+    pub level: u8,
+    pub charging: ChargingState,
+}
+
+impl BatteryStatus {
+    /// Decodes the battery byte of a USB input report (offset 53, including
+    /// the report ID). Low nibble is the level in tenths, high nibble the
+    /// charging state.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.first() != Some(&REPORT_ID_INPUT_USB) {
+            return None;
+        }
+        let status = *raw.get(53)?;
+        let charging = match status >> 4 {
+            0x0 => ChargingState::Discharging,
+            0x1 => ChargingState::Charging,
+            0x2 => ChargingState::Full,
+            _ => ChargingState::Error,
+        };
+        let level = match charging {
+            ChargingState::Full => 100,
+            _ => ((status & 0x0F) * 10 + 5).min(100),
+        };
+        Some(Self { level, charging })
+    }
+}
+
+/// Commands sent in the 0xF4 report. These four are the whole known update
+/// protocol: there is no command or report for reading flash back, so written
+/// data can only be checked through the device's own VerifyUpdateImage.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum UpdateCommand {
     StartUpdate = 0x00,
@@ -41,6 +411,17 @@ impl UpdateCommand {
             _ => Self::Unknown,
         }
     }
+
+    /// Short name used as the `phase` log field.
+    pub fn phase(self) -> &'static str {
+        match self {
+            Self::StartUpdate => "start",
+            Self::WriteUpdateImage => "write",
+            Self::VerifyUpdateImage => "verify",
+            Self::FinalizeUpdate => "finalize",
+            Self::Unknown => "unknown",
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -73,7 +454,6 @@ impl StartUpdateStatusCode {
         }
     }
 
-    #[allow(dead_code)]
     pub fn name(self) -> &'static str {
         match self {
             Self::Success => "SUCCESS",
@@ -156,7 +536,6 @@ impl VerifyUpdateStatusCode {
         }
     }
 
-    #[allow(dead_code)]
     pub fn name(self) -> &'static str {
         match self {
             Self::Success => "SUCCESS",
@@ -173,10 +552,273 @@ impl VerifyUpdateStatusCode {
 
 #[derive(Debug, Clone)]
 pub struct UpdateStatus {
-    #[allow(dead_code)]
     pub report_id: u8,
     pub command: UpdateCommand,
     pub status_raw: u8,
-    #[allow(dead_code)]
+    /// The last byte of the report, whose meaning isn't known. The report
+    /// has no room for a write position (the device only ever returns four
+    /// bytes), so dropped writes can't be caught from it; VerifyUpdateImage
+    /// is what catches them.
+    pub extra: u8,
     pub raw: Vec<u8>,
 }
+
+impl UpdateStatus {
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        if raw.is_empty() {
+            return Err(AppError::UpdateStatusEmpty);
+        }
+        if raw[0] != REPORT_ID_UPDATE_STATUS || raw.len() != 4 {
+            return Err(AppError::UpdateStatusMalformed(raw.len()));
+        }
+        // Keep the byte itself: a command we don't know usually means newer
+        // firmware, and `Unknown` alone would hide which one.
+        let command = UpdateCommand::from_int(raw[1]);
+        if command == UpdateCommand::Unknown {
+            return Err(AppError::UnknownStatusCommandByte(raw[1]));
+        }
+        Ok(Self {
+            report_id: raw[0],
+            command,
+            status_raw: raw[2],
+            extra: raw[3],
+            raw: raw.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn cmp_version_orders_known_releases() {
+        assert_eq!(FirmwareInfo::cmp_version(0x0520, 0x0600), Ordering::Less);
+        assert_eq!(FirmwareInfo::cmp_version(0x0600, 0x0630), Ordering::Less);
+        assert_eq!(FirmwareInfo::cmp_version(0x0630, 0x0520), Ordering::Greater);
+        assert_eq!(FirmwareInfo::cmp_version(0x0600, 0x0600), Ordering::Equal);
+    }
+
+    #[test]
+    fn cmp_version_minor_byte_boundaries() {
+        assert_eq!(FirmwareInfo::cmp_version(0x05FF, 0x0600), Ordering::Less);
+        assert_eq!(FirmwareInfo::cmp_version(0x0601, 0x0600), Ordering::Greater);
+        assert_eq!(FirmwareInfo::cmp_version(0x062F, 0x0630), Ordering::Less);
+        assert_eq!(FirmwareInfo::cmp_version(0x0520, 0x051F), Ordering::Greater);
+    }
+
+    /// A firmware info report as the controller sends it in `layout`, for
+    /// firmware 0x0520 built "Jun 10 2023 12:34:56".
+    // Hand-written reports in the 64-byte layout hid-playstation reads, not
+    // captures: the fields are spelled out byte by byte so these don't lean on
+    // the parser's own offsets.
+    const INFO_0520: [u8; 64] = [
+        0x20, 0x4a, 0x75, 0x6e, 0x20, 0x31, 0x30, 0x20, 0x32, 0x30, 0x32, 0x33,
+        0x31, 0x32, 0x3a, 0x33, 0x34, 0x3a, 0x35, 0x36, 0x00, 0x00, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x05, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const INFO_0630: [u8; 64] = [
+        0x20, 0x4d, 0x61, 0x72, 0x20, 0x32, 0x37, 0x20, 0x32, 0x30, 0x32, 0x34,
+        0x30, 0x39, 0x3a, 0x30, 0x35, 0x3a, 0x34, 0x31, 0x00, 0x00, 0x00, 0x00,
+        0x14, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x06, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn firmware_info_parses_with_report_id() {
+        let info = FirmwareInfo::parse(INFO_0520.to_vec()).unwrap();
+        assert_eq!(info.build_date, "Jun 10 2023");
+        assert_eq!(info.build_time, "12:34:56");
+        assert_eq!(info.firmware_version, 0x0520);
+        assert_eq!(info.hw_id, 0x0001_0203);
+
+        let info = FirmwareInfo::parse(INFO_0630.to_vec()).unwrap();
+        assert_eq!(info.build_date, "Mar 27 2024");
+        assert_eq!(info.build_time, "09:05:41");
+        assert_eq!(info.firmware_version, 0x0630);
+        assert_eq!(info.hw_id, 0x0000_0314);
+    }
+
+    #[test]
+    fn firmware_info_parses_without_report_id() {
+        let info = FirmwareInfo::parse(INFO_0520[1..].to_vec()).unwrap();
+        assert_eq!(info.build_date, "Jun 10 2023");
+        assert_eq!(info.build_time, "12:34:56");
+        assert_eq!(info.firmware_version, 0x0520);
+        assert_eq!(info.hw_id, 0x0001_0203);
+
+        let info = FirmwareInfo::parse(INFO_0630[1..].to_vec()).unwrap();
+        assert_eq!(info.build_date, "Mar 27 2024");
+        assert_eq!(info.build_time, "09:05:41");
+        assert_eq!(info.firmware_version, 0x0630);
+        assert_eq!(info.hw_id, 0x0000_0314);
+    }
+
+    #[test]
+    fn firmware_info_rejects_48_byte_report() {
+        let mut raw = INFO_0520.to_vec();
+        raw.truncate(48);
+        let err = FirmwareInfo::parse(raw.clone()).unwrap_err();
+        assert!(matches!(err, AppError::FirmwareInfoUnrecognized(48)), "{err:?}");
+        let err = FirmwareInfo::parse(raw[1..].to_vec()).unwrap_err();
+        assert!(matches!(err, AppError::FirmwareInfoUnrecognized(47)), "{err:?}");
+    }
+
+    #[test]
+    fn firmware_info_known_fields_match_64_byte_layout() {
+        let info = FirmwareInfo::parse(INFO_0520.to_vec()).unwrap();
+        assert_eq!(info.known_fields()[3], ("firmware_version", 44..46));
+        let info = FirmwareInfo::parse(INFO_0520[1..].to_vec()).unwrap();
+        assert_eq!(info.known_fields()[3], ("firmware_version", 43..45));
+    }
+
+    #[test]
+    fn firmware_info_rejects_unknown_length() {
+        let mut raw = INFO_0520.to_vec();
+        raw.resize(72, 0);
+        let err = FirmwareInfo::parse(raw).unwrap_err();
+        assert!(matches!(err, AppError::FirmwareInfoUnrecognized(72)), "{err:?}");
+    }
+
+    #[test]
+    fn update_status_rejects_empty() {
+        assert!(matches!(UpdateStatus::parse(&[]), Err(AppError::UpdateStatusEmpty)));
+    }
+
+    #[test]
+    fn update_status_rejects_wrong_report_id() {
+        let err = UpdateStatus::parse(&[REPORT_ID_FIRMWARE_INFO, 0x01, 0x03, 0x00]).unwrap_err();
+        assert!(matches!(err, AppError::UpdateStatusMalformed(4)), "{err:?}");
+    }
+
+    #[test]
+    fn update_status_rejects_wrong_length() {
+        let short = [REPORT_ID_UPDATE_STATUS, 0x01, 0x03];
+        let long = [REPORT_ID_UPDATE_STATUS, 0x01, 0x03, 0x00, 0x00];
+        for raw in [&short[..], &long[..]] {
+            let err = UpdateStatus::parse(raw).unwrap_err();
+            assert!(
+                matches!(err, AppError::UpdateStatusMalformed(len) if len == raw.len()),
+                "{err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn update_status_parses_valid_report() {
+        let status = UpdateStatus::parse(&[REPORT_ID_UPDATE_STATUS, 0x01, 0x03, 0x7E]).unwrap();
+        assert_eq!(status.command, UpdateCommand::WriteUpdateImage);
+        assert_eq!(status.status_raw, WriteUpdateStatusCode::SendNext as u8);
+        assert_eq!(status.extra, 0x7E);
+    }
+
+    fn image_header(version: u16) -> Vec<u8> {
+        let mut header = vec![0xA5; IMAGE_HEADER_LEN];
+        header[IMAGE_VERSION_OFFSET..IMAGE_VERSION_OFFSET + 2]
+            .copy_from_slice(&version.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn image_header_rejects_zero_version() {
+        let err = ImageHeader::parse(&image_header(0x0000)).unwrap_err();
+        assert!(matches!(err, AppError::ImageVersionImplausible(0x0000)), "{err:?}");
+    }
+
+    #[test]
+    fn image_header_rejects_erased_version() {
+        let err = ImageHeader::parse(&image_header(0xFFFF)).unwrap_err();
+        assert!(matches!(err, AppError::ImageVersionImplausible(0xFFFF)), "{err:?}");
+    }
+
+    #[test]
+    fn image_header_rejects_blank_header() {
+        for fill in [0x00, 0xFF] {
+            let err = ImageHeader::parse(&[fill; IMAGE_HEADER_LEN]).unwrap_err();
+            assert!(matches!(err, AppError::FirmwareImageMalformed(_)), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn image_header_accepts_valid_version() {
+        let header = ImageHeader::parse(&image_header(0x0520)).unwrap();
+        assert_eq!(header.firmware_version, 0x0520);
+        assert_eq!(header.body_len, 0);
+    }
+
+    // The parsers see whatever a controller, a backend or a file hands them,
+    // so none of them may panic on any input; a bad one has to be an error.
+    proptest! {
+        #[test]
+        fn image_header_parse_never_panics(
+            data in prop::collection::vec(any::<u8>(), 0..512),
+        ) {
+            let _ = ImageHeader::parse(&data);
+        }
+
+        #[test]
+        fn update_status_parse_never_panics(
+            raw in prop::collection::vec(any::<u8>(), 0..8),
+        ) {
+            let _ = UpdateStatus::parse(&raw);
+        }
+
+        #[test]
+        fn firmware_info_parse_never_panics(
+            raw in prop::collection::vec(any::<u8>(), 0..80),
+        ) {
+            let _ = FirmwareInfo::parse(raw);
+        }
+
+        #[test]
+        fn feature_report_len_never_panics(
+            descriptor in prop::collection::vec(any::<u8>(), 0..256),
+            report_id in any::<u8>(),
+        ) {
+            let _ = feature_report_len(&descriptor, report_id);
+        }
+    }
+
+    /// Inputs at the edges the property tests above may not land on, kept as
+    /// fixed regression seeds.
+    #[test]
+    fn parsers_survive_regression_seeds() {
+        let mut short_info = vec![REPORT_ID_FIRMWARE_INFO; 20];
+        short_info[1] = b'J';
+        // Report Size * Report Count overflows u32.
+        let overflow = [0x77, 0xFF, 0xFF, 0xFF, 0xFF, 0x97, 0xFF, 0xFF, 0xFF, 0xFF, 0xB1, 0x00];
+        let seeds: &[&[u8]] = &[
+            &[],
+            &[REPORT_ID_UPDATE_STATUS],
+            &[REPORT_ID_UPDATE_STATUS, 0xFF, 0x00, 0x00],
+            &short_info,
+            &[0x00; FIRMWARE_INFO_MIN_LEN - 1],
+            &[0xFF; 64],
+            &[0x41; 65],
+            &[0x00; IMAGE_HEADER_LEN - 1],
+            &[0xA5; IMAGE_VERSION_OFFSET + 1],
+            // Long item whose declared size runs past the end.
+            &[0xFE, 0xFF],
+            // 4-byte short item cut off after its prefix.
+            &[0x03],
+            &overflow,
+        ];
+        for seed in seeds {
+            let _ = ImageHeader::parse(seed);
+            let _ = UpdateStatus::parse(seed);
+            let _ = FirmwareInfo::parse(seed.to_vec());
+            for report_id in [0x00, REPORT_ID_FIRMWARE_INFO] {
+                let _ = feature_report_len(seed, report_id);
+            }
+        }
+        assert_eq!(feature_report_len(&overflow, 0), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! `--record` / `--replay`: a line-per-transfer log of everything that crossed
+//! the `HidTransport` boundary during a flash, and a transport that plays such
+//! a log back so a captured session can be re-run without hardware.
+//!
+//! Each line is `<kind> <hex bytes>`:
+//!
+//! - `info`: a firmware info report as received
+//! - `send`: the command byte followed by the whole payload
+//! - `status`: an update status report as received
+//!
+//! Lines starting with `#` are comments. Failed transfers are recorded as
+//! comments only, so a replay stops where the recorded run failed.
+
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+use crate::hid::HidTransport;
+use crate::protocol::{FirmwareInfo, UpdateCommand, UpdateStatus};
+
+const KIND_INFO: &str = "info";
+const KIND_SEND: &str = "send";
+const KIND_STATUS: &str = "status";
+
+/// Passes every call through to `inner` and logs it.
+pub struct RecordingHid<T: HidTransport> {
+    inner: T,
+    log: RefCell<LineWriter<File>>,
+}
+
+impl<T: HidTransport> RecordingHid<T> {
+    pub fn create(inner: T, path: &Path) -> Result<Self> {
+        let mut log = LineWriter::new(File::create(path)?);
+        writeln!(log, "# dualsense-updater transfer log")?;
+        Ok(Self {
+            inner,
+            log: RefCell::new(log),
+        })
+    }
+
+    fn record<R>(
+        &self,
+        kind: &str,
+        result: &Result<R>,
+        bytes: impl FnOnce(&R) -> Vec<u8>,
+    ) -> Result<()> {
+        let mut log = self.log.borrow_mut();
+        match result {
+            Ok(value) => writeln!(log, "{} {}", kind, encode_hex(&bytes(value)))?,
+            Err(err) => writeln!(log, "# {} failed: {}", kind, err)?,
+        }
+        Ok(())
+    }
+}
+
+impl<T: HidTransport> HidTransport for RecordingHid<T> {
+    fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
+        let result = self.inner.send_update_command(command, payload);
+        self.record(KIND_SEND, &result, |_| command_bytes(command, payload))?;
+        result
+    }
+
+    fn get_update_status(&self, length: usize) -> Result<UpdateStatus> {
+        let result = self.inner.get_update_status(length);
+        self.record(KIND_STATUS, &result, |status| status.raw.clone())?;
+        result
+    }
+
+    fn get_firmware_info(&self) -> Result<FirmwareInfo> {
+        let result = self.inner.get_firmware_info();
+        self.record(KIND_INFO, &result, |info| info.raw.clone())?;
+        result
+    }
+
+    fn max_update_payload(&self) -> usize {
+        self.inner.max_update_payload()
+    }
+}
+
+struct Transfer {
+    line: usize,
+    kind: String,
+    bytes: Vec<u8>,
+}
+
+/// Answers from a recorded log, checking that what's sent matches it.
+pub struct ReplayHid {
+    transfers: Vec<Transfer>,
+    next: Cell<usize>,
+}
+
+impl ReplayHid {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(log: &str) -> Result<Self> {
+        let mut transfers = Vec::new();
+        for (idx, text) in log.lines().enumerate() {
+            let line = idx + 1;
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let (kind, hex) = text.split_once(' ').unwrap_or((text, ""));
+            if ![KIND_INFO, KIND_SEND, KIND_STATUS].contains(&kind) {
+                return Err(AppError::ReplayLogMalformed { line });
+            }
+            let bytes = decode_hex(hex.trim()).ok_or(AppError::ReplayLogMalformed { line })?;
+            transfers.push(Transfer {
+                line,
+                kind: kind.to_string(),
+                bytes,
+            });
+        }
+        Ok(Self {
+            transfers,
+            next: Cell::new(0),
+        })
+    }
+
+    fn take(&self, kind: &str) -> Result<&Transfer> {
+        let idx = self.next.get();
+        let Some(transfer) = self.transfers.get(idx) else {
+            return Err(AppError::ReplayDiverged {
+                line: self.transfers.last().map_or(0, |t| t.line + 1),
+                expected: "end of log".to_string(),
+                got: kind.to_string(),
+            });
+        };
+        if transfer.kind != kind {
+            return Err(AppError::ReplayDiverged {
+                line: transfer.line,
+                expected: transfer.kind.clone(),
+                got: kind.to_string(),
+            });
+        }
+        self.next.set(idx + 1);
+        Ok(transfer)
+    }
+}
+
+impl HidTransport for ReplayHid {
+    fn send_update_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
+        let transfer = self.take(KIND_SEND)?;
+        let sent = command_bytes(command, payload);
+        if transfer.bytes != sent {
+            return Err(AppError::ReplayDiverged {
+                line: transfer.line,
+                expected: describe_send(&transfer.bytes),
+                got: describe_send(&sent),
+            });
+        }
+        Ok(())
+    }
+
+    fn get_update_status(&self, _length: usize) -> Result<UpdateStatus> {
+        UpdateStatus::parse(&self.take(KIND_STATUS)?.bytes)
+    }
+
+    fn get_firmware_info(&self) -> Result<FirmwareInfo> {
+        FirmwareInfo::parse(self.take(KIND_INFO)?.bytes.clone())
+    }
+}
+
+fn command_bytes(command: UpdateCommand, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 1);
+    bytes.push(command as u8);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn describe_send(bytes: &[u8]) -> String {
+    match bytes.split_first() {
+        Some((command, payload)) => format!(
+            "send {:?} with {} bytes",
+            UpdateCommand::from_int(*command),
+            payload.len()
+        ),
+        None => "empty send".to_string(),
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
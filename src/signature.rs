@@ -0,0 +1,67 @@
+//! `--verify-signature`: refuse an image unless it carries a valid ed25519
+//! signature from a key the operator controls. This is a gate on which
+//! images get flashed at all, checked on the host before anything is sent;
+//! it is independent of the controller's own CMAC checks of the header and
+//! body, which still apply to every image. Needs the `signature` feature.
+//!
+//! The signature covers the image bytes that would be written, i.e. after
+//! gzip decompression and `--image-offset`. The signature and public key
+//! files hold either the raw bytes (64 and 32) or the same as hex text.
+
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+
+#[cfg(feature = "signature")]
+const SIGNATURE_LEN: usize = 64;
+#[cfg(feature = "signature")]
+const PUBLIC_KEY_LEN: usize = 32;
+
+#[cfg(feature = "signature")]
+pub fn verify_image_signature(image: &[u8], signature: &Path, public_key: &Path) -> Result<()> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let signature = Signature::from_bytes(&read_key_file::<SIGNATURE_LEN>(signature)?);
+    let key = VerifyingKey::from_bytes(&read_key_file::<PUBLIC_KEY_LEN>(public_key)?)
+        .map_err(|err| AppError::SignatureCheckFailed(format!("invalid public key: {err}")))?;
+    key.verify_strict(image, &signature)
+        .map_err(|_| AppError::ImageSignatureInvalid)?;
+    log::info!("Image signature verified");
+    Ok(())
+}
+
+#[cfg(not(feature = "signature"))]
+pub fn verify_image_signature(_image: &[u8], _signature: &Path, _public_key: &Path) -> Result<()> {
+    Err(AppError::SignatureCheckFailed(
+        "this build has no signature support; rebuild with --features signature".to_string(),
+    ))
+}
+
+/// Reads exactly `N` bytes from `path`, stored raw or as hex text.
+#[cfg(feature = "signature")]
+fn read_key_file<const N: usize>(path: &Path) -> Result<[u8; N]> {
+    let data = std::fs::read(path).map_err(|err| {
+        AppError::SignatureCheckFailed(format!("can't read {}: {}", path.display(), err))
+    })?;
+    if let Ok(raw) = <[u8; N]>::try_from(data.as_slice()) {
+        return Ok(raw);
+    }
+    let text = std::str::from_utf8(&data).unwrap_or_default().trim();
+    let mut out = [0u8; N];
+    let decoded = text.len() == 2 * N
+        && out.iter_mut().enumerate().all(|(idx, byte)| {
+            text.get(2 * idx..2 * idx + 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .map(|value| *byte = value)
+                .is_some()
+        });
+    if !decoded {
+        return Err(AppError::SignatureCheckFailed(format!(
+            "{} is neither {} raw bytes nor {} hex digits",
+            path.display(),
+            N,
+            2 * N
+        )));
+    }
+    Ok(out)
+}
@@ -0,0 +1,120 @@
+//! `tui` feature: a progress sink that keeps a small status block redrawn in
+//! place at the bottom of the terminal, instead of printing a line per chunk.
+//!
+//! It only uses ANSI cursor movement, so there is no extra dependency; it is
+//! picked when stdout is a terminal, and the plain console sink is used
+//! otherwise.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::output;
+use crate::progress::ProgressSink;
+use crate::protocol::{UpdateCommand, WriteUpdateStatusCode};
+
+const PHASES: [UpdateCommand; 4] = [
+    UpdateCommand::StartUpdate,
+    UpdateCommand::WriteUpdateImage,
+    UpdateCommand::VerifyUpdateImage,
+    UpdateCommand::FinalizeUpdate,
+];
+
+const BAR_WIDTH: usize = 30;
+
+pub struct TuiProgress {
+    /// 0 when the image size isn't known up front; the bar is left out.
+    total_chunks: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    phase: Option<UpdateCommand>,
+    chunks_done: usize,
+    last_status: Option<WriteUpdateStatusCode>,
+    started: Instant,
+}
+
+impl TuiProgress {
+    pub fn new(total_chunks: usize) -> Self {
+        Self {
+            total_chunks,
+            state: Mutex::new(State {
+                phase: None,
+                chunks_done: 0,
+                last_status: None,
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    fn render(&self, state: &State) -> Vec<String> {
+        let current = state
+            .phase
+            .and_then(|phase| PHASES.iter().position(|p| *p == phase));
+        let mut lines: Vec<String> = PHASES
+            .iter()
+            .enumerate()
+            .map(|(idx, phase)| {
+                let mark = match current {
+                    Some(cur) if idx < cur => 'x',
+                    Some(cur) if idx == cur => '>',
+                    _ => ' ',
+                };
+                let mut line = format!("  [{}] {:?}", mark, phase);
+                let reached = current.is_some_and(|cur| cur >= idx);
+                if *phase == UpdateCommand::WriteUpdateImage && reached {
+                    line.push_str("  ");
+                    line.push_str(&self.write_progress(state.chunks_done));
+                }
+                line
+            })
+            .collect();
+        let status = match state.last_status {
+            Some(status) => format!("{} (0x{:02x})", status.name(), status as u8),
+            None => "-".to_string(),
+        };
+        lines.push(format!(
+            "  Elapsed {:.1}s, last status: {}",
+            state.started.elapsed().as_secs_f64(),
+            status
+        ));
+        lines
+    }
+
+    fn write_progress(&self, done: usize) -> String {
+        if self.total_chunks == 0 {
+            return format!("{} chunks", done);
+        }
+        let done = done.min(self.total_chunks);
+        let filled = BAR_WIDTH * done / self.total_chunks;
+        format!(
+            "[{}{}] {:3}%  {}/{} chunks",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            100 * done / self.total_chunks,
+            done,
+            self.total_chunks
+        )
+    }
+}
+
+impl ProgressSink for TuiProgress {
+    fn phase_started(&self, command: UpdateCommand) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.phase = Some(command);
+        output::draw_status(&self.render(&state));
+    }
+
+    /// Redraws so the elapsed time keeps moving during a long verify.
+    fn verify_waiting(&self, _elapsed: Duration, _timeout: Duration) {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        output::draw_status(&self.render(&state));
+    }
+
+    fn chunk_written(&self, idx: usize, status: WriteUpdateStatusCode) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.chunks_done = idx + 1;
+        state.last_status = Some(status);
+        output::draw_status(&self.render(&state));
+    }
+}
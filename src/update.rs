@@ -1,45 +1,142 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufReader, Read};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::digest::WriteDigest;
 use crate::error::{
     AppError, Result, StartUpdateError, UpdateFailure, VerifyUpdateImageError,
     WriteUpdateImageError,
 };
-use crate::hid::DualSenseHid;
+use crate::hid::{DualSenseHid, HidTransport};
+use crate::output;
+use crate::progress::{ConsoleProgress, ProgressSink};
 use crate::protocol::{
-    FirmwareInfo, StartUpdateStatusCode, UpdateCommand, VerifyUpdateStatusCode,
-    WriteUpdateStatusCode,
+    FirmwareInfo, ImageHeader, StartUpdateStatusCode, UpdateCommand, UpdateStatus,
+    VerifyUpdateStatusCode, VersionField, WriteUpdateStatusCode, MAX_IMAGE_LEN, MIN_IMAGE_LEN,
+    WRITE_CHUNK_LEN,
 };
 
-pub struct DualSenseUpdater {
-    dev: DualSenseHid,
+/// How far the update sequence has got on this updater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Idle,
+    Started,
+    Written,
+    Verified,
+    Finalized,
+}
+
+pub struct DualSenseUpdater<T: HidTransport = DualSenseHid> {
+    dev: T,
+    stage: Cell<Stage>,
+    /// Set by `release`: stopping before FinalizeUpdate was intended.
+    released: Cell<bool>,
+    cancel: Arc<AtomicBool>,
+    progress: Box<dyn ProgressSink + Send>,
+    poll_interval: Duration,
+    version_field: VersionField,
+    inter_chunk_delay: Duration,
+    verify_timeout: Duration,
 }
 
 impl DualSenseUpdater {
-    pub fn firmware_version_from_image(fw_image_path: &Path) -> Result<u16> {
-        let data = std::fs::read(fw_image_path)?;
-        let offset = 0x78usize;
-        if data.len() < offset + 2 {
-            return Err(AppError::FirmwareImageTooSmall);
+    /// Reads the target version from `version` (normally
+    /// `VersionField::default()`).
+    pub fn firmware_version_from_image(image: &[u8], version: VersionField) -> Result<u16> {
+        version.read(image)
+    }
+
+    pub fn firmware_version_from_path(fw_image_path: &Path) -> Result<u16> {
+        Self::firmware_version_from_image(&std::fs::read(fw_image_path)?, VersionField::default())
+    }
+
+    /// Checks the image is structurally sound before anything is sent to the
+    /// device.
+    pub fn validate_image(image: &[u8], version: VersionField) -> Result<ImageHeader> {
+        if !(MIN_IMAGE_LEN..=MAX_IMAGE_LEN).contains(&image.len()) {
+            return Err(AppError::FirmwareImageImplausibleSize { len: image.len() });
+        }
+        let header = ImageHeader::parse_with_version_field(image, version)?;
+        header.validate_body(image)?;
+        Ok(header)
+    }
+}
+
+impl<T: HidTransport> DualSenseUpdater<T> {
+    pub fn new(dev: T) -> Self {
+        Self::with_cancel_token(dev, Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Creates an updater that stops with `AppError::Cancelled` once `cancel`
+    /// is set. It's checked before each 0x8000-byte chunk and between status
+    /// polls, never inside a HID transfer. Cancelling after StartUpdate leaves
+    /// the controller with an incomplete image; re-run the update to recover.
+    pub fn with_cancel_token(dev: T, cancel: Arc<AtomicBool>) -> Self {
+        Self {
+            dev,
+            stage: Cell::new(Stage::Idle),
+            released: Cell::new(false),
+            cancel,
+            progress: Box::new(ConsoleProgress),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            version_field: VersionField::default(),
+            inter_chunk_delay: Duration::ZERO,
+            verify_timeout: DEFAULT_VERIFY_TIMEOUT,
         }
-        Ok(u16::from_le_bytes([data[offset], data[offset + 1]]))
     }
 
-    pub fn new(dev: DualSenseHid) -> Self {
-        Self { dev }
+    /// Reads the target version from `version` instead of the default
+    /// field in `start_update`.
+    pub fn with_version_field(mut self, version: VersionField) -> Self {
+        self.version_field = version;
+        self
+    }
+
+    /// Sets the sleep between status polls, clamped to 1..=1000ms.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval.clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL);
+        self
+    }
+
+    /// Sleeps this long after each acknowledged 0x8000-byte chunk before
+    /// sending the next.
+    pub fn with_inter_chunk_delay(mut self, delay: Duration) -> Self {
+        self.inter_chunk_delay = delay;
+        self
+    }
+
+    /// Gives up on VerifyUpdateImage with `AppError::UpdateTimeout` if the
+    /// device still answers KeepPolling after this long.
+    pub fn with_verify_timeout(mut self, timeout: Duration) -> Self {
+        self.verify_timeout = timeout;
+        self
+    }
+
+    /// Replaces the default console output for per-chunk progress.
+    pub fn with_progress(mut self, progress: Box<dyn ProgressSink + Send>) -> Self {
+        self.progress = progress;
+        self
     }
 
     pub fn read_firmware_info(&self) -> Result<FirmwareInfo> {
         self.dev.get_firmware_info()
     }
 
-    pub fn start_update(&self, fw_image_path: &Path) -> Result<()> {
-        let data = std::fs::read(fw_image_path)?;
-        if data.len() < 256 {
-            return Err(AppError::FirmwareImageTooSmallForHeader);
-        }
-        let status = self.send_start_update_and_wait(&data[..256])?;
+    pub fn read_update_status(&self) -> Result<UpdateStatus> {
+        self.dev.get_update_status(4)
+    }
+
+    /// Sends the image header and returns it parsed, so callers know the
+    /// target version without reading the image again.
+    pub fn start_update(&self, image: &[u8]) -> Result<ImageHeader> {
+        let header = ImageHeader::parse_with_version_field(image, self.version_field)?;
+        self.progress.phase_started(UpdateCommand::StartUpdate);
+        let status = self.send_start_update_and_wait(&image[..256])?;
         let failure = match status {
             StartUpdateStatusCode::Success => None,
             StartUpdateStatusCode::Processing | StartUpdateStatusCode::Retry => None,
@@ -64,44 +161,96 @@ impl DualSenseUpdater {
         if let Some(err) = failure {
             return Err(AppError::UpdateFailed(UpdateFailure::StartUpdate(err)));
         }
-        Ok(())
+        self.advance(Stage::Started);
+        Ok(header)
     }
 
-    pub fn write_update_image(&self, fw_image_path: &Path) -> Result<()> {
-        let image = std::fs::read(fw_image_path)?;
-        let chunk_size = 0x8000usize;
-        for (idx, chunk) in image.chunks(chunk_size).enumerate() {
-            let status = self.send_write_update_image_and_wait(chunk)?;
-            println!(
-                "WriteUpdateImage chunk {}: {} (0x{:02x})",
-                idx,
-                status.name(),
-                status as u8
-            );
-            let failure = match status {
-                WriteUpdateStatusCode::Success | WriteUpdateStatusCode::SendNext => None,
-                WriteUpdateStatusCode::Retry | WriteUpdateStatusCode::AlsoRetry => None,
-                WriteUpdateStatusCode::WriteImageFlashWriteError => {
-                    Some(WriteUpdateImageError::WriteImageFlashWriteError)
-                }
-                WriteUpdateStatusCode::WriteUpdateNotStarted => {
-                    Some(WriteUpdateImageError::WriteUpdateNotStarted)
-                }
-                WriteUpdateStatusCode::WriteImageCommonParamError => {
-                    Some(WriteUpdateImageError::WriteImageCommonParamError)
-                }
-                WriteUpdateStatusCode::WriteImageOtherError => {
-                    Some(WriteUpdateImageError::WriteImageOtherError)
-                }
-            };
-            if let Some(err) = failure {
-                return Err(AppError::UpdateFailed(UpdateFailure::WriteUpdateImage(err)));
+    pub fn start_update_from_path(&self, fw_image_path: &Path) -> Result<ImageHeader> {
+        self.start_update(&std::fs::read(fw_image_path)?)
+    }
+
+    /// Writes the whole image and returns the digest of the bytes sent.
+    pub fn write_update_image(&self, image: &[u8]) -> Result<WriteSummary> {
+        self.write_update_image_from(image, 0)
+    }
+
+    /// Streams the image from the file instead of loading it into memory.
+    pub fn write_update_image_from_path(&self, fw_image_path: &Path) -> Result<WriteSummary> {
+        self.write_update_image_streaming(BufReader::new(File::open(fw_image_path)?))
+    }
+
+    /// Writes the image starting at outer chunk `first_chunk`. Only valid if
+    /// the device already accepted StartUpdate and the earlier chunks.
+    pub fn write_update_image_from(
+        &self,
+        image: &[u8],
+        first_chunk: usize,
+    ) -> Result<WriteSummary> {
+        self.progress.phase_started(UpdateCommand::WriteUpdateImage);
+        let mut polls = PollStats::new(UpdateCommand::WriteUpdateImage);
+        let mut written = WriteProgress::new();
+        for (idx, chunk) in image.chunks(WRITE_CHUNK_LEN).enumerate().skip(first_chunk) {
+            self.write_chunk(idx, chunk, &mut polls)?;
+            written.add(chunk);
+        }
+        polls.report();
+        self.advance(Stage::Written);
+        Ok(written.finish(&polls))
+    }
+
+    /// Like `write_update_image`, but reads one 0x8000-byte chunk at a time
+    /// from `reader`, so the whole image never has to be in memory.
+    pub fn write_update_image_streaming(&self, mut reader: impl Read) -> Result<WriteSummary> {
+        self.progress.phase_started(UpdateCommand::WriteUpdateImage);
+        let mut polls = PollStats::new(UpdateCommand::WriteUpdateImage);
+        let mut written = WriteProgress::new();
+        let mut chunk = Vec::with_capacity(WRITE_CHUNK_LEN);
+        for idx in 0.. {
+            chunk.clear();
+            (&mut reader).take(WRITE_CHUNK_LEN as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
             }
+            self.write_chunk(idx, &chunk, &mut polls)?;
+            written.add(&chunk);
+        }
+        polls.report();
+        self.advance(Stage::Written);
+        Ok(written.finish(&polls))
+    }
+
+    fn write_chunk(&self, idx: usize, chunk: &[u8], polls: &mut PollStats) -> Result<()> {
+        self.check_cancelled(UpdateCommand::WriteUpdateImage)?;
+        let status = self.send_write_update_image_and_wait(idx, chunk, polls)?;
+        self.progress.chunk_written(idx, status);
+        let failure = match status {
+            WriteUpdateStatusCode::Success | WriteUpdateStatusCode::SendNext => None,
+            WriteUpdateStatusCode::Retry | WriteUpdateStatusCode::AlsoRetry => None,
+            WriteUpdateStatusCode::WriteImageFlashWriteError => {
+                Some(WriteUpdateImageError::WriteImageFlashWriteError)
+            }
+            WriteUpdateStatusCode::WriteUpdateNotStarted => {
+                Some(WriteUpdateImageError::WriteUpdateNotStarted)
+            }
+            WriteUpdateStatusCode::WriteImageCommonParamError => {
+                Some(WriteUpdateImageError::WriteImageCommonParamError)
+            }
+            WriteUpdateStatusCode::WriteImageOtherError => {
+                Some(WriteUpdateImageError::WriteImageOtherError)
+            }
+        };
+        if let Some(err) = failure {
+            return Err(AppError::UpdateFailed(UpdateFailure::WriteUpdateImage(err)));
+        }
+        self.advance(Stage::Started);
+        if !self.inter_chunk_delay.is_zero() {
+            thread::sleep(self.inter_chunk_delay);
         }
         Ok(())
     }
 
     pub fn verify_update_image(&self) -> Result<()> {
+        self.progress.phase_started(UpdateCommand::VerifyUpdateImage);
         let status = self.send_verify_update_image_and_wait()?;
         let failure = match status {
             VerifyUpdateStatusCode::Success => None,
@@ -126,21 +275,64 @@ impl DualSenseUpdater {
         if let Some(err) = failure {
             return Err(AppError::UpdateFailed(UpdateFailure::VerifyUpdateImage(err)));
         }
+        self.advance(Stage::Verified);
         Ok(())
     }
 
+    /// Meant to back out of an update after StartUpdate, leaving the old
+    /// firmware running. The update protocol has no such command: once
+    /// StartUpdate has prepared the flash, the only way out is to write,
+    /// verify and finalize a complete image. So this always fails and sends
+    /// nothing.
+    pub fn abort_update(&self) -> Result<()> {
+        Err(AppError::AbortNotSupported)
+    }
+
+    /// Commits the update. Refused unless `verify_update_image` succeeded on
+    /// this updater.
     pub fn finalize_update(&self) -> Result<()> {
+        if self.stage.get() < Stage::Verified {
+            return Err(AppError::FinalizeWithoutVerify);
+        }
+        self.send_finalize_update()
+    }
+
+    /// Commits the update without checking that it was verified first.
+    pub fn finalize_update_unchecked(&self) -> Result<()> {
         self.send_finalize_update()
     }
 
+    /// Marks stopping short of FinalizeUpdate as deliberate (e.g.
+    /// `--no-finalize` or a `--*-only` step), so dropping the updater doesn't
+    /// warn that the controller was left mid-update.
+    pub fn release(&self) {
+        self.released.set(true);
+    }
+
+    /// Moves the stage forward, never back: a write-only run (after a
+    /// StartUpdate sent earlier) still counts as started.
+    fn advance(&self, stage: Stage) {
+        if stage > self.stage.get() {
+            self.stage.set(stage);
+        }
+    }
+
+    fn check_cancelled(&self, during: UpdateCommand) -> Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(AppError::Cancelled { during });
+        }
+        Ok(())
+    }
+
     fn send_start_update_and_wait(&self, data: &[u8]) -> Result<StartUpdateStatusCode> {
         if data.len() != 256 {
             return Err(AppError::InvalidUpdateStreamLength(data.len()));
         }
-        self.dev
-            .send_update_command(UpdateCommand::StartUpdate, data)?;
+        self.send_command(UpdateCommand::StartUpdate, data)?;
+        let mut polls = PollStats::new(UpdateCommand::StartUpdate);
         loop {
-            let status = self.dev.get_update_status(4)?;
+            self.check_cancelled(UpdateCommand::StartUpdate)?;
+            let status = self.poll_status()?;
             if status.command != UpdateCommand::StartUpdate {
                 return Err(AppError::UnexpectedUpdateStatusCommand(
                     status.command,
@@ -148,47 +340,75 @@ impl DualSenseUpdater {
                 ));
             }
             if status.status_raw != StartUpdateStatusCode::Processing as u8 {
+                polls.report();
                 return Ok(StartUpdateStatusCode::from_int(status.status_raw));
             }
-            thread::sleep(Duration::from_millis(10));
+            polls.count();
+            thread::sleep(self.poll_interval);
         }
     }
 
     fn send_write_update_image_and_wait(
         &self,
+        chunk_idx: usize,
         data: &[u8],
+        polls: &mut PollStats,
     ) -> Result<WriteUpdateStatusCode> {
-        if data.len() > 0x8000 {
+        if data.len() > WRITE_CHUNK_LEN {
             return Err(AppError::UpdateImageTooLarge(data.len()));
         }
-        let max_chunk = 0x39usize;
+        let max_chunk = self.dev.max_update_payload();
         let offsets: Vec<usize> = if data.is_empty() {
             vec![0]
         } else {
             (0..data.len()).step_by(max_chunk).collect()
         };
+        let mut retries = 0u32;
         for off in offsets {
             let chunk = &data[off..data.len().min(off + max_chunk)];
-            self.dev
-                .send_update_command(UpdateCommand::WriteUpdateImage, chunk)?;
+            // Each report is its own command here, so place it in the 0x8000-byte
+            // chunk for the F4 line that follows.
+            let sent = format!("{}/{}", off + chunk.len(), data.len());
+            log::debug!(
+                chunk = chunk_idx, off = off, sent = sent.as_str();
+                "WriteUpdateImage report"
+            );
+            self.send_command(UpdateCommand::WriteUpdateImage, chunk)?;
             loop {
-                let status = self.dev.get_update_status(4)?;
+                self.check_cancelled(UpdateCommand::WriteUpdateImage)?;
+                let status = self.poll_status()?;
                 if status.command != UpdateCommand::WriteUpdateImage {
-                return Err(AppError::UnexpectedUpdateStatusCommand(
-                    status.command,
-                    UpdateCommand::WriteUpdateImage,
-                ));
-            }
+                    return Err(AppError::StatusCommandDrift {
+                        got: status.command,
+                        expected: UpdateCommand::WriteUpdateImage,
+                        chunk: chunk_idx,
+                    });
+                }
                 let status_code = WriteUpdateStatusCode::from_int(status.status_raw);
                 if status_code == WriteUpdateStatusCode::Retry
                     || status_code == WriteUpdateStatusCode::AlsoRetry
                 {
-                    thread::sleep(Duration::from_millis(10));
+                    polls.count();
+                    retries += 1;
+                    if retries == RETRY_WARN_THRESHOLD {
+                        output::warn(format!(
+                            "Chunk {} retried {} times, the connection may be marginal",
+                            chunk_idx, retries
+                        ));
+                    }
+                    if retries >= RETRY_LIMIT {
+                        return Err(AppError::TooManyRetries {
+                            chunk: chunk_idx,
+                            count: retries,
+                        });
+                    }
+                    thread::sleep(self.poll_interval);
                     continue;
                 }
                 if status_code == WriteUpdateStatusCode::SendNext
                     || status_code == WriteUpdateStatusCode::Success
                 {
+                    retries = 0;
                     break;
                 }
                 return Ok(status_code);
@@ -198,10 +418,12 @@ impl DualSenseUpdater {
     }
 
     fn send_verify_update_image_and_wait(&self) -> Result<VerifyUpdateStatusCode> {
-        self.dev
-            .send_update_command(UpdateCommand::VerifyUpdateImage, &[])?;
+        self.send_command(UpdateCommand::VerifyUpdateImage, &[])?;
+        let mut polls = PollStats::new(UpdateCommand::VerifyUpdateImage);
+        let mut next_report = VERIFY_REPORT_INTERVAL;
         loop {
-            let status = self.dev.get_update_status(4)?;
+            self.check_cancelled(UpdateCommand::VerifyUpdateImage)?;
+            let status = self.poll_status()?;
             if status.command != UpdateCommand::VerifyUpdateImage {
                 return Err(AppError::UnexpectedUpdateStatusCommand(
                     status.command,
@@ -210,16 +432,248 @@ impl DualSenseUpdater {
             }
             let status_code = VerifyUpdateStatusCode::from_int(status.status_raw);
             if status_code == VerifyUpdateStatusCode::KeepPolling {
-                thread::sleep(Duration::from_millis(10));
+                polls.count();
+                let elapsed = polls.started.elapsed();
+                if elapsed >= self.verify_timeout {
+                    polls.report();
+                    return Err(AppError::UpdateTimeout {
+                        command: UpdateCommand::VerifyUpdateImage,
+                        elapsed,
+                    });
+                }
+                if elapsed >= next_report {
+                    self.progress.verify_waiting(elapsed, self.verify_timeout);
+                    next_report += VERIFY_REPORT_INTERVAL;
+                }
+                thread::sleep(self.poll_interval);
                 continue;
             }
+            polls.report();
             return Ok(status_code);
         }
     }
 
+    fn send_command(&self, command: UpdateCommand, payload: &[u8]) -> Result<()> {
+        self.progress.command_sent(command, payload);
+        self.dev.send_update_command(command, payload)
+    }
+
+    fn poll_status(&self) -> Result<UpdateStatus> {
+        let status = self.dev.get_update_status(4)?;
+        self.progress.status_received(&status);
+        Ok(status)
+    }
+
     fn send_finalize_update(&self) -> Result<()> {
-        self.dev
-            .send_update_command(UpdateCommand::FinalizeUpdate, &[])?;
+        self.progress.phase_started(UpdateCommand::FinalizeUpdate);
+        self.send_command(UpdateCommand::FinalizeUpdate, &[])?;
+        self.advance(Stage::Finalized);
         Ok(())
     }
 }
+
+/// A safety net for callers that stop partway through by accident, e.g. on
+/// an error: the controller is left with an uncommitted image, which only a
+/// full re-run fixes. Deliberate stops call `release` first.
+impl<T: HidTransport> Drop for DualSenseUpdater<T> {
+    fn drop(&mut self) {
+        let stage = self.stage.get();
+        if stage != Stage::Idle && stage != Stage::Finalized && !self.released.get() {
+            log::warn!(
+                "Updater dropped at stage {:?} without FinalizeUpdate; the controller is left mid-update",
+                stage
+            );
+        }
+    }
+}
+
+/// Consecutive Retry responses within one 0x8000-byte chunk before warning,
+/// and before giving up on it.
+const RETRY_WARN_THRESHOLD: u32 = 50;
+const RETRY_LIMIT: u32 = 1000;
+
+/// Sleep between status polls while the device is busy.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Verify checks the whole image on the device and is much slower than the
+/// other phases, so it gets its own limit on KeepPolling answers.
+pub const DEFAULT_VERIFY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often to say verify is still running.
+const VERIFY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Polls above this many in one phase suggest a flaky connection.
+const POLL_WARN_THRESHOLD: u32 = 1000;
+
+/// What a WriteUpdateImage run sent.
+#[derive(Debug, Clone)]
+pub struct WriteSummary {
+    pub chunks_written: usize,
+    pub bytes_written: usize,
+    /// Retry responses from the device, across all chunks.
+    pub retries: u32,
+    pub elapsed: Duration,
+    /// Digest of the bytes written, as `<algorithm>:<hex>`.
+    pub digest: String,
+}
+
+/// Running totals for a `WriteSummary`.
+struct WriteProgress {
+    chunks: usize,
+    bytes: usize,
+    digest: WriteDigest,
+}
+
+impl WriteProgress {
+    fn new() -> Self {
+        Self {
+            chunks: 0,
+            bytes: 0,
+            digest: WriteDigest::new(),
+        }
+    }
+
+    fn add(&mut self, chunk: &[u8]) {
+        self.chunks += 1;
+        self.bytes += chunk.len();
+        self.digest.update(chunk);
+    }
+
+    fn finish(self, polls: &PollStats) -> WriteSummary {
+        WriteSummary {
+            chunks_written: self.chunks,
+            bytes_written: self.bytes,
+            retries: polls.polls,
+            elapsed: polls.started.elapsed(),
+            digest: self.digest.finish(),
+        }
+    }
+}
+
+/// Counts the Processing/Retry/KeepPolling responses seen during a phase.
+struct PollStats {
+    command: UpdateCommand,
+    polls: u32,
+    started: Instant,
+}
+
+impl PollStats {
+    fn new(command: UpdateCommand) -> Self {
+        Self {
+            command,
+            polls: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn count(&mut self) {
+        self.polls += 1;
+    }
+
+    fn report(&self) {
+        let elapsed = self.started.elapsed().as_millis();
+        if self.polls > POLL_WARN_THRESHOLD {
+            log::warn!(
+                "{:?} polled {} times over {}ms; the connection may be unreliable",
+                self.command,
+                self.polls,
+                elapsed
+            );
+        } else {
+            log::info!("{:?} polled {} times over {}ms", self.command, self.polls, elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_hid::MockHid;
+    use crate::progress::QuietProgress;
+    use crate::protocol::{IMAGE_VERSION_OFFSET, MAX_UPDATE_PAYLOAD};
+
+    fn updater(dev: MockHid) -> DualSenseUpdater<MockHid> {
+        DualSenseUpdater::new(dev)
+            .with_progress(Box::new(QuietProgress))
+            .with_poll_interval(Duration::ZERO)
+    }
+
+    /// A header the host-side checks accept, with version 0x0520.
+    fn header() -> Vec<u8> {
+        let mut header = vec![0xA5; 256];
+        header[IMAGE_VERSION_OFFSET..IMAGE_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x0520u16.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn start_update_polls_until_done() {
+        let dev = MockHid::new().statuses(
+            UpdateCommand::StartUpdate,
+            &[
+                StartUpdateStatusCode::Processing as u8,
+                StartUpdateStatusCode::Processing as u8,
+                StartUpdateStatusCode::Success as u8,
+            ],
+        );
+        let updater = updater(dev);
+        let status = updater.send_start_update_and_wait(&header()).unwrap();
+        assert_eq!(status, StartUpdateStatusCode::Success);
+        assert_eq!(updater.dev.remaining(), 0);
+        let sent = updater.dev.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0], (UpdateCommand::StartUpdate, header()));
+    }
+
+    #[test]
+    fn start_update_maps_header_cmac_check_error() {
+        let dev = MockHid::new().statuses(
+            UpdateCommand::StartUpdate,
+            &[
+                StartUpdateStatusCode::Processing as u8,
+                StartUpdateStatusCode::HeaderCmacCheckError as u8,
+            ],
+        );
+        let updater = updater(dev);
+        let err = updater.start_update(&header()).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AppError::UpdateFailed(UpdateFailure::StartUpdate(
+                    StartUpdateError::HeaderCmacCheckError
+                ))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(updater.stage.get(), Stage::Idle);
+    }
+
+    #[test]
+    fn write_reports_status_drift_with_chunk_index() {
+        let reports_per_chunk = WRITE_CHUNK_LEN.div_ceil(MAX_UPDATE_PAYLOAD);
+        let send_next = vec![WriteUpdateStatusCode::SendNext as u8; 2 * reports_per_chunk];
+        // Chunk 2's first report is answered with a VerifyUpdateImage status.
+        let dev = MockHid::new()
+            .statuses(UpdateCommand::WriteUpdateImage, &send_next)
+            .statuses(
+                UpdateCommand::VerifyUpdateImage,
+                &[VerifyUpdateStatusCode::Success as u8],
+            );
+        let updater = updater(dev);
+        let image = vec![0x5A; 3 * WRITE_CHUNK_LEN];
+        let err = updater.write_update_image(&image).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AppError::StatusCommandDrift {
+                    got: UpdateCommand::VerifyUpdateImage,
+                    expected: UpdateCommand::WriteUpdateImage,
+                    chunk: 2,
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(updater.dev.sent().len(), 2 * reports_per_chunk + 1);
+    }
+}
@@ -0,0 +1,57 @@
+//! Async wrappers around `DualSenseUpdater` for front-ends running on a tokio
+//! runtime. Each call runs on tokio's blocking thread pool, so the HID I/O and
+//! the status polling sleeps never stall the executor. The image is passed
+//! in as shared bytes, read once by the caller rather than once per phase.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::error::{AppError, Result};
+use crate::hid::{DualSenseHid, HidTransport};
+use crate::protocol::{FirmwareInfo, ImageHeader};
+use crate::update::{DualSenseUpdater, WriteSummary};
+
+pub struct AsyncDualSenseUpdater<T: HidTransport = DualSenseHid> {
+    inner: Arc<Mutex<DualSenseUpdater<T>>>,
+}
+
+impl<T: HidTransport + Send + 'static> AsyncDualSenseUpdater<T> {
+    pub fn new(updater: DualSenseUpdater<T>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(updater)),
+        }
+    }
+
+    pub async fn read_firmware_info(&self) -> Result<FirmwareInfo> {
+        self.run(|updater| updater.read_firmware_info()).await
+    }
+
+    pub async fn start_update(&self, image: Arc<[u8]>) -> Result<ImageHeader> {
+        self.run(move |updater| updater.start_update(&image)).await
+    }
+
+    pub async fn write_update_image(&self, image: Arc<[u8]>) -> Result<WriteSummary> {
+        self.run(move |updater| updater.write_update_image(&image)).await
+    }
+
+    pub async fn verify_update_image(&self) -> Result<()> {
+        self.run(|updater| updater.verify_update_image()).await
+    }
+
+    pub async fn finalize_update(&self) -> Result<()> {
+        self.run(|updater| updater.finalize_update()).await
+    }
+
+    async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&DualSenseUpdater<T>) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || {
+            let updater = inner.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&updater)
+        })
+        .await
+        .map_err(|err| AppError::Io(std::io::Error::other(err)))?
+    }
+}